@@ -0,0 +1,62 @@
+use chrono::Utc;
+use tracing::error;
+
+use crate::{AuthCommands, load_auth_info, load_configuration, refresh_token_if_needed, webhook::is_bridge_subscription, backend::{ThermostatBackend, SmartherBackend}};
+
+pub(crate) async fn auth_command(command: &AuthCommands, auth_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    match command {
+        AuthCommands::Status => auth_status(auth_file),
+        AuthCommands::Refresh => auth_refresh(auth_file).await,
+        AuthCommands::Logout => auth_logout(auth_file, configuration_file).await
+    }
+}
+
+fn auth_status(auth_file: &str) -> anyhow::Result<()> {
+    let auth_info = load_auth_info(auth_file)?;
+    let expires_on = auth_info.expires_on;
+    let remaining = expires_on - Utc::now();
+    println!("Tokens file:     {}", auth_file);
+    println!("Expires at:      {}", expires_on.to_rfc3339());
+    if remaining.num_seconds() > 0 {
+        println!("Expires in:      {}h {}m", remaining.num_hours(), remaining.num_minutes() % 60);
+    } else {
+        println!("Expires in:      expired");
+    }
+    // The token endpoint response is reduced to AuthorizationInfo, which has no scope field
+    println!("Scopes:          unknown, the tokens file does not record the granted scopes");
+    println!("Refresh needed:  {}", auth_info.is_refresh_needed());
+    Ok(())
+}
+
+async fn auth_refresh(auth_file: &str) -> anyhow::Result<()> {
+    let auth_info = load_auth_info(auth_file)?;
+    let previous_expiration = auth_info.expires_on;
+    let auth_info = refresh_token_if_needed(&SmartherBackend, auth_info, auth_file).await?;
+    if auth_info.expires_on != previous_expiration {
+        println!("Token refreshed, now expires at {}", auth_info.expires_on.to_rfc3339());
+    } else {
+        println!("Token still valid until {}, no refresh needed", auth_info.expires_on.to_rfc3339());
+    }
+    Ok(())
+}
+
+async fn auth_logout(auth_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    let configuration = load_configuration(configuration_file)?;
+    let auth_info = load_auth_info(auth_file)?;
+    let backend = SmartherBackend;
+    let auth_info = refresh_token_if_needed(&backend, auth_info, auth_file).await?;
+
+    let subscriptions = backend.get_webhooks(&auth_info).await?;
+    for subscription in subscriptions.iter().filter(|subscription| is_bridge_subscription(&configuration, subscription)) {
+        if let Some(plant_id) = &subscription.plant_id {
+            match backend.unregister_webhook(&auth_info, plant_id, &subscription.subscription_id).await {
+                Ok(_) => println!("Unregistered webhook {} for plant {}", &subscription.subscription_id, plant_id),
+                Err(err) => error!("Failed to unregister webhook {}: {}", &subscription.subscription_id, err)
+            }
+        }
+    }
+
+    std::fs::remove_file(auth_file)?;
+    println!("Removed stored tokens from {}", auth_file);
+    Ok(())
+}
@@ -0,0 +1,359 @@
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc, Duration};
+use smarther::{model::{PlantDetail, ModuleStatus, SetStatusRequest, SubscriptionInfo}, AuthorizationInfo, SmartherApi};
+use tracing::{info_span, field, Instrument};
+
+use crate::logging::request_id;
+
+/// Calls the bridge makes against the Smarther cloud.
+#[async_trait(?Send)]
+pub(crate) trait ThermostatBackend {
+    async fn refresh_token(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<AuthorizationInfo>;
+    async fn get_plants(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<String>>;
+    async fn get_topology(&self, auth_info: &AuthorizationInfo, plant_id: &str) -> anyhow::Result<PlantDetail>;
+    async fn get_device_status(&self, auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str) -> anyhow::Result<ModuleStatus>;
+    async fn set_device_status(&self, auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str, request: SetStatusRequest) -> anyhow::Result<()>;
+    async fn get_webhooks(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<SubscriptionInfo>>;
+    async fn register_webhook(&self, auth_info: &AuthorizationInfo, plant_id: &str, endpoint_url: String) -> anyhow::Result<SubscriptionInfo>;
+    async fn unregister_webhook(&self, auth_info: &AuthorizationInfo, plant_id: &str, subscription_id: &str) -> anyhow::Result<()>;
+}
+
+/// Backend talking to the real Smarther cloud.
+#[derive(Default)]
+pub(crate) struct SmartherBackend;
+
+async fn api_call<T>(operation: &'static str, plant_id: Option<&str>, module_id: Option<&str>, call: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    let span = info_span!("api_call", request_id = %request_id(), operation, plant_id, module_id, outcome = field::Empty);
+    let result = call.instrument(span.clone()).await;
+    span.record("outcome", if result.is_ok() { "ok" } else { "error" });
+    result
+}
+
+#[async_trait(?Send)]
+impl ThermostatBackend for SmartherBackend {
+    async fn refresh_token(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<AuthorizationInfo> {
+        api_call("refresh_token", None, None, SmartherApi::default().refresh_token(auth_info)).await
+    }
+
+    async fn get_plants(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<String>> {
+        api_call("get_plants", None, None, async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            let plants = client.get_plants().await?;
+            Ok(plants.plants.into_iter().map(|plant| plant.id).collect())
+        }).await
+    }
+
+    async fn get_topology(&self, auth_info: &AuthorizationInfo, plant_id: &str) -> anyhow::Result<PlantDetail> {
+        api_call("get_topology", Some(plant_id), None, async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            Ok(client.get_topology(plant_id).await?.plant)
+        }).await
+    }
+
+    async fn get_device_status(&self, auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str) -> anyhow::Result<ModuleStatus> {
+        api_call("get_device_status", Some(plant_id), Some(module_id), async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            client.get_device_status(plant_id, module_id).await
+        }).await
+    }
+
+    async fn set_device_status(&self, auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str, request: SetStatusRequest) -> anyhow::Result<()> {
+        api_call("set_device_status", Some(plant_id), Some(module_id), async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            client.set_device_status(plant_id, module_id, request).await?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_webhooks(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<SubscriptionInfo>> {
+        api_call("get_webhooks", None, None, async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            client.get_webhooks().await
+        }).await
+    }
+
+    async fn register_webhook(&self, auth_info: &AuthorizationInfo, plant_id: &str, endpoint_url: String) -> anyhow::Result<SubscriptionInfo> {
+        api_call("register_webhook", Some(plant_id), None, async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            client.register_webhook(plant_id, endpoint_url).await
+        }).await
+    }
+
+    async fn unregister_webhook(&self, auth_info: &AuthorizationInfo, plant_id: &str, subscription_id: &str) -> anyhow::Result<()> {
+        api_call("unregister_webhook", Some(plant_id), None, async {
+            let client = SmartherApi::default().with_authorization(auth_info.clone())?;
+            client.unregister_webhook(plant_id, subscription_id).await?;
+            Ok(())
+        }).await
+    }
+}
+
+/// Chronothermostat state rendered in the Smarther API format.
+pub(crate) struct ThermostatSnapshot<'a> {
+    pub plant_id: &'a str,
+    pub module_id: &'a str,
+    pub mode: &'a str,
+    pub load_state: &'a str,
+    pub set_point: f64,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub time: DateTime<Utc>,
+}
+
+impl<'a> ThermostatSnapshot<'a> {
+    pub fn new(plant_id: &'a str, module_id: &'a str, time: DateTime<Utc>) -> Self {
+        Self {
+            plant_id,
+            module_id,
+            mode: "AUTOMATIC",
+            load_state: "ACTIVE",
+            set_point: 20.0,
+            temperature: 19.5,
+            humidity: 45.0,
+            time
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let time = self.time.to_rfc3339();
+        serde_json::json!({
+            "chronothermostats": [{
+                "function": "HEATING",
+                "mode": self.mode,
+                "setPoint": { "value": format!("{:.1}", self.set_point), "unit": "C" },
+                "programs": [{ "number": 1 }],
+                "temperatureFormat": "C",
+                "loadState": self.load_state,
+                "time": time,
+                "thermometer": { "flags": [], "measures": [{ "timeStamp": time, "value": format!("{:.1}", self.temperature), "unit": "C" }] },
+                "hygrometer": { "flags": [], "measures": [{ "timeStamp": time, "value": format!("{:.0}", self.humidity), "unit": "%" }] },
+                "sender": { "addressType": "module", "system": "smarther", "plant": { "id": self.plant_id, "module": { "id": self.module_id } } }
+            }]
+        })
+    }
+
+    pub fn to_module_status(&self) -> anyhow::Result<ModuleStatus> {
+        Ok(serde_json::from_value(self.to_json())?)
+    }
+}
+
+/// Command received by a [`FakeBackend`] through `set_device_status`.
+#[derive(Debug, Serialize)]
+pub(crate) struct FakeCommand {
+    pub plant_id: String,
+    pub module_id: String,
+    pub request: SetStatusRequest,
+}
+
+#[derive(Default)]
+struct FakeState {
+    plants: Vec<PlantDetail>,
+    statuses: HashMap<(String, String), ModuleStatus>,
+    subscriptions: Vec<SubscriptionInfo>,
+    commands: Vec<FakeCommand>,
+    next_subscription_id: u32,
+    refresh_error: Option<String>,
+}
+
+/// In-memory backend holding a configurable topology, for tests and offline runs.
+#[derive(Default)]
+pub(crate) struct FakeBackend {
+    state: Mutex<FakeState>,
+}
+
+impl FakeBackend {
+    pub fn with_plant(self, plant: PlantDetail) -> Self {
+        self.state.lock().unwrap().plants.push(plant);
+        self
+    }
+
+    pub fn with_status(self, plant_id: &str, module_id: &str, status: ModuleStatus) -> Self {
+        self.set_status(plant_id, module_id, status);
+        self
+    }
+
+    /// Makes every token refresh fail with the given error.
+    #[cfg(test)]
+    pub fn with_refresh_error(self, error: &str) -> Self {
+        self.state.lock().unwrap().refresh_error = Some(error.to_string());
+        self
+    }
+
+    pub fn set_status(&self, plant_id: &str, module_id: &str, status: ModuleStatus) {
+        self.state.lock().unwrap().statuses.insert((plant_id.to_string(), module_id.to_string()), status);
+    }
+
+    pub fn plants(&self) -> Vec<PlantDetail> {
+        self.state.lock().unwrap().plants.clone()
+    }
+
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.state.lock().unwrap().subscriptions.clone()
+    }
+
+    /// Returns and forgets the commands received so far.
+    pub fn take_commands(&self) -> Vec<FakeCommand> {
+        std::mem::take(&mut self.state.lock().unwrap().commands)
+    }
+
+    /// Authorization that never needs a refresh, to be used together with this backend.
+    pub fn authorization() -> AuthorizationInfo {
+        serde_json::from_value(serde_json::json!({
+            "client_id": "fake",
+            "client_secret": "fake",
+            "subscription_key": "fake",
+            "base_uri": null,
+            "access_token": "fake",
+            "refresh_token": "fake",
+            "expires_on": Utc::now() + Duration::days(365)
+        })).expect("Fake authorization should always deserialize")
+    }
+
+    pub fn plant(&self, plant_id: &str) -> anyhow::Result<PlantDetail> {
+        self.state.lock().unwrap().plants.iter()
+            .find(|plant| plant.id == plant_id)
+            .cloned()
+            .ok_or(anyhow!("Unknown plant {}", plant_id))
+    }
+
+    pub fn status(&self, plant_id: &str, module_id: &str) -> anyhow::Result<ModuleStatus> {
+        let state = self.state.lock().unwrap();
+        FakeBackend::ensure_module(&state, plant_id, module_id)?;
+        state.statuses.get(&(plant_id.to_string(), module_id.to_string()))
+            .cloned()
+            .ok_or(anyhow!("No status for module {} in plant {}", module_id, plant_id))
+    }
+
+    pub fn record_command(&self, plant_id: &str, module_id: &str, request: SetStatusRequest) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        FakeBackend::ensure_module(&state, plant_id, module_id)?;
+        state.commands.push(FakeCommand { plant_id: plant_id.to_string(), module_id: module_id.to_string(), request });
+        Ok(())
+    }
+
+    pub fn add_subscription(&self, plant_id: &str, endpoint_url: String) -> anyhow::Result<SubscriptionInfo> {
+        let mut state = self.state.lock().unwrap();
+        if !state.plants.iter().any(|plant| plant.id == plant_id) {
+            return Err(anyhow!("Unknown plant {}", plant_id));
+        }
+        state.next_subscription_id += 1;
+        let subscription = SubscriptionInfo {
+            plant_id: Some(plant_id.to_string()),
+            subscription_id: format!("subscription-{}", state.next_subscription_id),
+            endpoint_url
+        };
+        state.subscriptions.push(subscription.clone());
+        Ok(subscription)
+    }
+
+    pub fn remove_subscription(&self, plant_id: &str, subscription_id: &str) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let subscriptions_count = state.subscriptions.len();
+        state.subscriptions.retain(|subscription| !(subscription.plant_id.as_deref() == Some(plant_id) && subscription.subscription_id == subscription_id));
+        if state.subscriptions.len() == subscriptions_count {
+            return Err(anyhow!("Unknown subscription {}", subscription_id));
+        }
+        Ok(())
+    }
+
+    fn ensure_module(state: &FakeState, plant_id: &str, module_id: &str) -> anyhow::Result<()> {
+        let plant = state.plants.iter().find(|plant| plant.id == plant_id).ok_or(anyhow!("Unknown plant {}", plant_id))?;
+        if !plant.modules.iter().any(|module| module.id == module_id) {
+            return Err(anyhow!("Unknown module {} in plant {}", module_id, plant_id));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl ThermostatBackend for FakeBackend {
+    async fn refresh_token(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<AuthorizationInfo> {
+        match &self.state.lock().unwrap().refresh_error {
+            Some(error) => Err(anyhow!("Failed to refresh token: {}", error)),
+            None => Ok(auth_info.clone())
+        }
+    }
+
+    async fn get_plants(&self, _auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<String>> {
+        Ok(self.plants().into_iter().map(|plant| plant.id).collect())
+    }
+
+    async fn get_topology(&self, _auth_info: &AuthorizationInfo, plant_id: &str) -> anyhow::Result<PlantDetail> {
+        self.plant(plant_id)
+    }
+
+    async fn get_device_status(&self, _auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str) -> anyhow::Result<ModuleStatus> {
+        self.status(plant_id, module_id)
+    }
+
+    async fn set_device_status(&self, _auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str, request: SetStatusRequest) -> anyhow::Result<()> {
+        self.record_command(plant_id, module_id, request)
+    }
+
+    async fn get_webhooks(&self, _auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<SubscriptionInfo>> {
+        Ok(self.subscriptions())
+    }
+
+    async fn register_webhook(&self, _auth_info: &AuthorizationInfo, plant_id: &str, endpoint_url: String) -> anyhow::Result<SubscriptionInfo> {
+        self.add_subscription(plant_id, endpoint_url)
+    }
+
+    async fn unregister_webhook(&self, _auth_info: &AuthorizationInfo, plant_id: &str, subscription_id: &str) -> anyhow::Result<()> {
+        self.remove_subscription(plant_id, subscription_id)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::rc::Rc;
+
+    use chrono::{DateTime, Utc};
+    use smarther::model::{PlantDetail, ModuleStatus};
+
+    use crate::{Context, BridgeConfiguration, CachedTopology};
+
+    use super::{FakeBackend, ThermostatSnapshot};
+
+    pub const PLANT_ID: &str = "plant-1";
+    pub const MODULE_ID: &str = "module-1";
+
+    pub fn plant() -> PlantDetail {
+        serde_json::from_value(serde_json::json!({
+            "id": PLANT_ID,
+            "name": "Home",
+            "modules": [{ "device": "chronothermostat", "id": MODULE_ID, "name": "Living Room" }]
+        })).unwrap()
+    }
+
+    /// Time of the measurements in fixture statuses.
+    pub fn time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2023-04-10T10:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    pub fn module_status_json(plant_id: &str, module_id: &str, temperature: f64) -> serde_json::Value {
+        ThermostatSnapshot { temperature, ..ThermostatSnapshot::new(plant_id, module_id, time()) }.to_json()
+    }
+
+    pub fn module_status(plant_id: &str, module_id: &str, temperature: f64) -> ModuleStatus {
+        serde_json::from_value(module_status_json(plant_id, module_id, temperature)).unwrap()
+    }
+
+    pub fn backend() -> Rc<FakeBackend> {
+        Rc::new(FakeBackend::default()
+            .with_plant(plant())
+            .with_status(PLANT_ID, MODULE_ID, module_status(PLANT_ID, MODULE_ID, 19.5)))
+    }
+
+    pub fn context(backend: Rc<FakeBackend>) -> Context {
+        context_with_configuration(backend, BridgeConfiguration::default())
+    }
+
+    pub fn context_with_configuration(backend: Rc<FakeBackend>, configuration: BridgeConfiguration) -> Context {
+        let topology = CachedTopology { plants: backend.plants() };
+        let auth_file = std::env::temp_dir().join("smarther-bridge-test-tokens.json").to_string_lossy().to_string();
+        Context::new(configuration, topology, FakeBackend::authorization(), auth_file, backend)
+    }
+}
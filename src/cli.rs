@@ -0,0 +1,251 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc, Duration, SecondsFormat};
+use smarther::{model::{SetStatusRequest, ThermostatStatus, ThermostatMode, ThermostatFunction, LoadState, Measurement, TimedMeasurement}, AuthorizationInfo};
+
+use crate::{StatusArgs, SetArgs, SetMode, OutputFormat, WebhookCommands, CachedTopology, load_auth_info, load_configuration, load_topology, refresh_token_if_needed, backend::{ThermostatBackend, SmartherBackend}, webhook::{webhook_url, is_bridge_subscription}};
+
+#[derive(Debug, Serialize)]
+struct ModuleReport {
+    plant_id: String,
+    module_id: String,
+    name: String,
+    mode: ThermostatMode,
+    function: ThermostatFunction,
+    set_point: Option<Measurement>,
+    temperature: Option<TimedMeasurement>,
+    humidity: Option<TimedMeasurement>,
+    load_state: Option<LoadState>,
+    time: DateTime<Utc>,
+    activation_time: Option<DateTime<Utc>>,
+}
+
+impl ModuleReport {
+    fn new(module: &ModuleSelection, status: &ThermostatStatus) -> Self {
+        ModuleReport {
+            plant_id: module.plant_id.clone(),
+            module_id: module.module_id.clone(),
+            name: module.name.clone(),
+            mode: status.mode.clone(),
+            function: status.function.clone(),
+            set_point: status.set_point.clone(),
+            temperature: status.thermometer.as_ref().and_then(|instrument| instrument.last_measurement()).cloned(),
+            humidity: status.hygrometer.as_ref().and_then(|instrument| instrument.last_measurement()).cloned(),
+            load_state: status.load_state.clone(),
+            time: status.time,
+            activation_time: status.activation_time
+        }
+    }
+}
+
+struct ModuleSelection {
+    plant_id: String,
+    module_id: String,
+    name: String,
+}
+
+async fn authorize(backend: &dyn ThermostatBackend, auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
+    let auth_info = load_auth_info(auth_file).map_err(|err| anyhow!("Failed to load tokens from {}, run setup first: {}", auth_file, err))?;
+    refresh_token_if_needed(backend, auth_info, auth_file).await
+}
+
+fn select_modules(topology: &CachedTopology, plant: Option<&str>, module: Option<&str>) -> Vec<ModuleSelection> {
+    topology.plants.iter()
+        .filter(|candidate| plant.is_none_or(|plant| candidate.id == plant))
+        .flat_map(|candidate| candidate.modules.iter().map(move |module| (candidate, module)))
+        .filter(|(_, candidate)| module.is_none_or(|module| candidate.id == module))
+        .map(|(plant, module)| ModuleSelection { plant_id: plant.id.clone(), module_id: module.id.clone(), name: module.name.clone() })
+        .collect()
+}
+
+fn optional<T>(value: Option<T>, format: impl Fn(T) -> String) -> String {
+    value.map(format).unwrap_or_else(|| "-".to_string())
+}
+
+fn print_table(reports: &[ModuleReport]) {
+    println!("{:<38} {:<38} {:<20} {:<12} {:<10} {:<10} {:<12} {:<10} {:<10}", "PLANT", "MODULE", "NAME", "MODE", "FUNCTION", "SET POINT", "TEMPERATURE", "HUMIDITY", "LOAD");
+    for report in reports {
+        println!("{:<38} {:<38} {:<20} {:<12} {:<10} {:<10} {:<12} {:<10} {:<10}",
+            report.plant_id,
+            report.module_id,
+            report.name,
+            format!("{:?}", report.mode),
+            format!("{:?}", report.function),
+            optional(report.set_point.as_ref(), |set_point| format!("{}{}", set_point.value, set_point.unit)),
+            optional(report.temperature.as_ref(), |temperature| format!("{}{}", temperature.value, temperature.unit)),
+            optional(report.humidity.as_ref(), |humidity| format!("{}{}", humidity.value, humidity.unit)),
+            optional(report.load_state.as_ref(), |load_state| format!("{:?}", load_state)));
+    }
+}
+
+pub(crate) async fn status(args: &StatusArgs, auth_file: &str, topology_file: &str) -> anyhow::Result<()> {
+    let backend = SmartherBackend;
+    let auth_info = authorize(&backend, auth_file).await?;
+    let topology = load_topology(false, &backend, &auth_info, topology_file).await?;
+
+    let modules = select_modules(&topology, args.plant.as_deref(), args.module.as_deref());
+    if modules.is_empty() {
+        return Err(anyhow!("No module matches the given plant and module"));
+    }
+
+    let mut reports = vec!();
+    for module in modules {
+        let module_status = backend.get_device_status(&auth_info, &module.plant_id, &module.module_id).await?;
+        for thermostat_status in &module_status.chronothermostats {
+            reports.push(ModuleReport::new(&module, thermostat_status));
+        }
+    }
+
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&reports)?),
+        OutputFormat::Table => print_table(&reports)
+    }
+    Ok(())
+}
+
+fn status_request(args: &SetArgs) -> anyhow::Result<SetStatusRequest> {
+    Ok(serde_json::from_value(status_request_json(args, Utc::now())?)?)
+}
+
+fn status_request_json(args: &SetArgs, now: DateTime<Utc>) -> anyhow::Result<serde_json::Value> {
+    let mode = match args.mode {
+        SetMode::Automatic => "AUTOMATIC",
+        SetMode::Manual => "MANUAL",
+        SetMode::Boost => "BOOST",
+        SetMode::Off => "OFF",
+        SetMode::Protection => "PROTECTION"
+    };
+
+    let mut request = serde_json::json!({
+        "function": args.function.to_uppercase(),
+        "mode": mode
+    });
+
+    match (args.mode, args.temperature) {
+        (SetMode::Manual, None) => return Err(anyhow!("The manual mode requires --temperature")),
+        (_, Some(temperature)) => request["setPoint"] = serde_json::json!({ "value": format!("{:.1}", temperature), "unit": "C" }),
+        _ => {}
+    }
+
+    if let Some(program) = args.program {
+        request["programs"] = serde_json::json!([{ "number": program }]);
+    }
+
+    if let Some(duration) = args.duration {
+        // With an explicit offset, a bare local time would be read in the plant time zone
+        let activation_time = now + Duration::minutes(duration);
+        request["activationTime"] = serde_json::json!(activation_time.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+
+    Ok(request)
+}
+
+pub(crate) async fn set(args: &SetArgs, auth_file: &str, topology_file: &str) -> anyhow::Result<()> {
+    let request = status_request(args)?;
+    let backend = SmartherBackend;
+    let auth_info = authorize(&backend, auth_file).await?;
+    let topology = load_topology(false, &backend, &auth_info, topology_file).await?;
+
+    let modules = select_modules(&topology, args.plant.as_deref(), args.module.as_deref());
+    let module = match modules.as_slice() {
+        [module] => module,
+        [] => return Err(anyhow!("No module matches the given plant and module")),
+        _ => return Err(anyhow!("Several modules match, please specify --plant and --module"))
+    };
+
+    println!("Setting status for {} ({}/{}) to {:?}", module.name, module.plant_id, module.module_id, request);
+    backend.set_device_status(&auth_info, &module.plant_id, &module.module_id, request).await?;
+    println!("Done");
+    Ok(())
+}
+
+pub(crate) async fn topology(auth_file: &str, topology_file: &str) -> anyhow::Result<()> {
+    let cached_topology = std::fs::read_to_string(topology_file).ok()
+        .and_then(|content| serde_json::from_str::<CachedTopology>(&content).ok());
+    let topology = match cached_topology {
+        Some(topology) => topology,
+        None => {
+            let backend = SmartherBackend;
+            let auth_info = authorize(&backend, auth_file).await?;
+            load_topology(false, &backend, &auth_info, topology_file).await?
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&topology)?);
+    Ok(())
+}
+
+pub(crate) async fn webhooks(command: &WebhookCommands, auth_file: &str, topology_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    let configuration = load_configuration(configuration_file)?;
+    let backend = SmartherBackend;
+    let auth_info = authorize(&backend, auth_file).await?;
+
+    match command {
+        WebhookCommands::List => {
+            let subscriptions = backend.get_webhooks(&auth_info).await?;
+            println!("{:<38} {:<38} {:<7} ENDPOINT", "PLANT", "SUBSCRIPTION", "BRIDGE");
+            for subscription in &subscriptions {
+                println!("{:<38} {:<38} {:<7} {}",
+                    subscription.plant_id.as_deref().unwrap_or("-"),
+                    subscription.subscription_id,
+                    if is_bridge_subscription(&configuration, subscription) { "yes" } else { "no" },
+                    subscription.endpoint_url);
+            }
+        },
+        WebhookCommands::Register { plant, endpoint } => {
+            let endpoint = endpoint.clone().or(configuration.webhook_endpoint.clone())
+                .ok_or(anyhow!("No webhook_endpoint configured, pass --endpoint"))?;
+            let topology = load_topology(false, &backend, &auth_info, topology_file).await?;
+            for plant in topology.plants.iter().filter(|candidate| plant.as_ref().is_none_or(|plant| &candidate.id == plant)) {
+                let subscription = backend.register_webhook(&auth_info, &plant.id, webhook_url(&endpoint, &plant.id)).await?;
+                println!("Registered {} for plant {}: {}", subscription.subscription_id, plant.id, subscription.endpoint_url);
+            }
+        },
+        WebhookCommands::Unregister { all, id } => {
+            let subscriptions = backend.get_webhooks(&auth_info).await?;
+            let targets: Vec<_> = subscriptions.iter()
+                .filter(|subscription| *all || id.as_ref() == Some(&subscription.subscription_id))
+                .collect();
+            if targets.is_empty() {
+                return Err(anyhow!("No subscription matches"));
+            }
+
+            for subscription in targets {
+                let plant_id = subscription.plant_id.as_ref().ok_or(anyhow!("Subscription {} has no plant", subscription.subscription_id))?;
+                backend.unregister_webhook(&auth_info, plant_id, &subscription.subscription_id).await?;
+                println!("Unregistered {} for plant {}", subscription.subscription_id, plant_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc, Duration};
+
+    use crate::{SetArgs, SetMode};
+
+    use super::{status_request, status_request_json};
+
+    fn set_args(mode: SetMode, temperature: Option<f64>, duration: Option<i64>) -> SetArgs {
+        SetArgs { plant: None, module: None, mode, function: "heating".to_string(), temperature, program: None, duration }
+    }
+
+    #[test]
+    fn manual_request_carries_zoned_activation_time() {
+        let now = Utc::now();
+        let args = set_args(SetMode::Manual, Some(21.5), Some(90));
+
+        let request = status_request_json(&args, now).unwrap();
+        assert_eq!(request["function"], "HEATING");
+        assert_eq!(request["mode"], "MANUAL");
+        assert_eq!(request["setPoint"]["value"], "21.5");
+        let activation_time = DateTime::parse_from_rfc3339(request["activationTime"].as_str().unwrap()).unwrap();
+        assert_eq!(activation_time.timestamp(), (now + Duration::minutes(90)).timestamp());
+        assert!(status_request(&args).is_ok());
+    }
+
+    #[test]
+    fn manual_request_requires_temperature() {
+        assert!(status_request(&set_args(SetMode::Manual, None, None)).is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use std::{path::Path, time::{Duration, SystemTime}};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use tracing::{info, warn, error};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Context, BridgeConfiguration, CachedTopology, ConfigCommands, load_configuration, topics::{Topics, TopicTemplates, duplicate_aliases}};
+
+const CONFIGURATION_FILE_STEM: &str = "configuration";
+const CONFIGURATION_POLL_SECONDS: u64 = 5;
+
+/// Keys telling whether a file was written before the topics were named after aliases.
+#[derive(Deserialize)]
+struct TopicKeys {
+    mqtt_topics: Option<IgnoredAny>,
+    aliases: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum ConfigurationFormat {
+    Json,
+    Toml,
+    Yaml
+}
+
+impl ConfigurationFormat {
+    pub fn from_path(file: &str) -> anyhow::Result<Self> {
+        match Path::new(file).extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(ConfigurationFormat::Json),
+            Some("toml") => Ok(ConfigurationFormat::Toml),
+            Some("yaml") | Some("yml") => Ok(ConfigurationFormat::Yaml),
+            _ => Err(anyhow!("Unsupported configuration format for {}, use .json, .toml, .yaml or .yml", file))
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ConfigurationFormat::Json => "json",
+            ConfigurationFormat::Toml => "toml",
+            ConfigurationFormat::Yaml => "yaml"
+        }
+    }
+
+    pub fn parse(&self, content: &str) -> anyhow::Result<BridgeConfiguration> {
+        Ok(self.parse_reporting_unknown_keys(content)?.0)
+    }
+
+    /// Parses the configuration and collects the keys serde ignored.
+    fn parse_reporting_unknown_keys(&self, content: &str) -> anyhow::Result<(BridgeConfiguration, Vec<String>)> {
+        let mut unknown_keys = vec!();
+        let mut configuration: BridgeConfiguration = match self {
+            ConfigurationFormat::Json => serde_ignored::deserialize(&mut serde_json::Deserializer::from_str(content), |path| unknown_keys.push(path.to_string()))?,
+            ConfigurationFormat::Toml => serde_ignored::deserialize(toml::Deserializer::new(content), |path| unknown_keys.push(path.to_string()))?,
+            ConfigurationFormat::Yaml => serde_ignored::deserialize(serde_yaml::Deserializer::from_str(content), |path| unknown_keys.push(path.to_string()))?
+        };
+        // Files older than the aliases keep publishing on the id topics their automations use
+        let topic_keys: TopicKeys = self.deserialize(content)?;
+        if topic_keys.mqtt_topics.is_none() && topic_keys.aliases.is_none() {
+            configuration.mqtt_topics = TopicTemplates::by_id();
+        }
+        Ok((configuration, unknown_keys))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, content: &str) -> anyhow::Result<T> {
+        match self {
+            ConfigurationFormat::Json => Ok(serde_json::from_str(content)?),
+            ConfigurationFormat::Toml => Ok(toml::from_str(content)?),
+            ConfigurationFormat::Yaml => Ok(serde_yaml::from_str(content)?)
+        }
+    }
+
+    pub fn serialize(&self, configuration: &BridgeConfiguration) -> anyhow::Result<String> {
+        match self {
+            ConfigurationFormat::Json => Ok(serde_json::to_string_pretty(configuration)?),
+            ConfigurationFormat::Toml => Ok(toml::to_string_pretty(configuration)?),
+            ConfigurationFormat::Yaml => Ok(serde_yaml::to_string(configuration)?)
+        }
+    }
+}
+
+/// First existing configuration file in the directory, JSON when there is none yet.
+pub(crate) fn default_configuration_file(config_dir: &str) -> String {
+    ["json", "toml", "yaml", "yml"].iter()
+        .map(|extension| format!("{}/{}.{}", config_dir, CONFIGURATION_FILE_STEM, extension))
+        .find(|file| Path::new(file).is_file())
+        .unwrap_or_else(|| format!("{}/{}.json", config_dir, CONFIGURATION_FILE_STEM))
+}
+
+/// Reads the configuration file, failing when it is missing or unreadable.
+pub(crate) fn read_configuration(configuration_file: &str) -> anyhow::Result<BridgeConfiguration> {
+    let content = std::fs::read_to_string(configuration_file)?;
+    ConfigurationFormat::from_path(configuration_file)?.parse(&content)
+}
+
+/// Checks the configuration, and its aliases against the topology when there is one.
+pub(crate) fn validate_configuration(configuration: &BridgeConfiguration, topology: Option<&CachedTopology>) -> anyhow::Result<()> {
+    let problems = configuration_problems(configuration, topology);
+    if !problems.is_empty() {
+        return Err(anyhow!(problems.join(", ")));
+    }
+    Ok(())
+}
+
+fn configuration_problems(configuration: &BridgeConfiguration, topology: Option<&CachedTopology>) -> Vec<String> {
+    let mut problems = vec!();
+    if configuration.mqtt_broker.trim().is_empty() {
+        problems.push("mqtt_broker is empty".to_string());
+    }
+    let base_topic = &configuration.mqtt_base_topic;
+    if base_topic.is_empty() || base_topic.starts_with('/') || base_topic.ends_with('/') || base_topic.contains(['+', '#']) {
+        problems.push(format!("mqtt_base_topic {} must be non empty, without wildcards and without leading or trailing /", base_topic));
+    }
+    if configuration.mqtt_port == 0 {
+        problems.push("mqtt_port must be between 1 and 65535".to_string());
+    }
+    if configuration.listen_port == 0 {
+        problems.push("listen_port must be between 1 and 65535".to_string());
+    }
+    if configuration.shutdown_timeout_seconds == 0 {
+        problems.push("shutdown_timeout_seconds must be greater than 0".to_string());
+    }
+    if configuration.mqtt_command_workers == 0 {
+        problems.push("mqtt_command_workers must be greater than 0".to_string());
+    }
+    if configuration.mqtt_reconnect_max_delay_seconds == 0 {
+        problems.push("mqtt_reconnect_max_delay_seconds must be greater than 0".to_string());
+    }
+    if let Some(client_id) = &configuration.mqtt_client_id {
+        if client_id.trim().is_empty() {
+            problems.push("mqtt_client_id is empty, remove it to use the derived one".to_string());
+        } else if client_id.trim() != client_id || client_id.chars().any(char::is_control) {
+            problems.push(format!("mqtt_client_id {:?} must not have surrounding whitespace nor control characters", client_id));
+        }
+    }
+    // rumqttc refuses shorter keepalives
+    if configuration.mqtt_keep_alive_seconds < 5 {
+        problems.push("mqtt_keep_alive_seconds must be at least 5".to_string());
+    }
+    if configuration.mqtt_inflight == 0 {
+        problems.push("mqtt_inflight must be greater than 0".to_string());
+    }
+    if configuration.mqtt_channel_capacity == 0 {
+        problems.push("mqtt_channel_capacity must be greater than 0".to_string());
+    }
+    if let Err(err) = Topics::new(configuration) {
+        problems.push(err.to_string());
+    }
+    for (id, alias) in &configuration.aliases {
+        if alias.is_empty() || alias.contains(['/', '+', '#']) {
+            problems.push(format!("alias {} of {} must be non empty, without wildcards and without /", alias, id));
+        }
+    }
+    if let Some(topology) = topology {
+        problems.extend(duplicate_aliases(topology, &configuration.aliases));
+    }
+    if let Some(endpoint) = &configuration.webhook_endpoint {
+        match reqwest::Url::parse(endpoint) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {},
+            Ok(_) => problems.push(format!("webhook_endpoint {} must be an http(s) URL", endpoint)),
+            Err(err) => problems.push(format!("webhook_endpoint {} is not a valid URL: {}", endpoint, err))
+        }
+    }
+    problems
+}
+
+/// Creates and removes a file, permission bits alone do not tell whether the process can write.
+fn is_writable(directory: &Path) -> bool {
+    let probe = directory.join(format!(".smarther-bridge-write-test-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        },
+        Err(_) => false
+    }
+}
+
+/// Checks that the directory of a file the bridge writes exists and is writable.
+fn path_problem(name: &str, file: &str) -> Option<String> {
+    match Path::new(file).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) if !parent.is_dir() => Some(format!("directory {} of {} {} does not exist", parent.display(), name, file)),
+        Some(parent) if !is_writable(parent) => Some(format!("directory {} of {} {} is not writable", parent.display(), name, file)),
+        None if !is_writable(Path::new(".")) => Some(format!("current directory of {} {} is not writable", name, file)),
+        _ => None
+    }
+}
+
+fn validate(auth_file: &str, topology_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    let mut problems = vec!();
+
+    // Without a topology cache the aliases cannot be told apart between plants and modules
+    let topology = std::fs::read_to_string(topology_file).ok()
+        .and_then(|content| serde_json::from_str::<CachedTopology>(&content).ok());
+    if Path::new(configuration_file).is_file() {
+        let content = std::fs::read_to_string(configuration_file)?;
+        match ConfigurationFormat::from_path(configuration_file).and_then(|format| format.parse_reporting_unknown_keys(&content)) {
+            Ok((configuration, unknown_keys)) => {
+                problems.extend(unknown_keys.into_iter().map(|key| format!("unknown key {}", key)));
+                problems.extend(configuration_problems(&configuration, topology.as_ref()));
+                if let Some(record_file) = &configuration.webhook_record_file {
+                    problems.extend(path_problem("webhook_record_file", record_file));
+                }
+            },
+            Err(err) => problems.push(format!("cannot parse {}: {}", configuration_file, err))
+        }
+    } else {
+        println!("{} not found, the defaults would be used", configuration_file);
+        problems.extend(path_problem("configuration", configuration_file));
+    }
+
+    if !Path::new(auth_file).is_file() {
+        println!("{} not found, run setup or use the onboarding page to create it", auth_file);
+    }
+    problems.extend(path_problem("tokens file", auth_file));
+    problems.extend(path_problem("topology cache", topology_file));
+
+    if problems.is_empty() {
+        println!("{} is valid", configuration_file);
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("- {}", problem);
+    }
+    Err(anyhow!("{} problems found", problems.len()))
+}
+
+fn convert(configuration_file: &str, format: ConfigurationFormat, output: Option<&str>) -> anyhow::Result<()> {
+    let configuration = load_configuration(configuration_file)?;
+    let output = output.map(str::to_string).unwrap_or_else(|| {
+        Path::new(configuration_file).with_extension(format.extension()).to_string_lossy().to_string()
+    });
+    if output == configuration_file {
+        return Err(anyhow!("{} is already in the {} format", configuration_file, format.extension()));
+    }
+
+    std::fs::write(&output, format.serialize(&configuration)?)?;
+    println!("Written {}, remove {} to start using it", output, configuration_file);
+    Ok(())
+}
+
+pub(crate) fn config_command(command: &ConfigCommands, auth_file: &str, topology_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    match command {
+        ConfigCommands::Validate => validate(auth_file, topology_file, configuration_file),
+        ConfigCommands::Convert { to, output } => convert(configuration_file, *to, output.as_deref())
+    }
+}
+
+fn modified(file: &str) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(unix)]
+type ReloadSignal = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type ReloadSignal = ();
+
+#[cfg(unix)]
+fn reload_signal() -> ReloadSignal {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup())
+        .map_err(|err| warn!("Failed to listen for SIGHUP, only file changes will reload the configuration: {}", err))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn reload_signal() -> ReloadSignal {}
+
+#[cfg(unix)]
+async fn reload_requested(signal: &mut ReloadSignal) {
+    match signal {
+        Some(signal) => { signal.recv().await; },
+        None => std::future::pending().await
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_requested(_signal: &mut ReloadSignal) {
+    std::future::pending().await
+}
+
+fn reload_configuration(context: &Context, configuration_file: &str) {
+    // Unlike startup, a missing file must not bring back the defaults
+    let mut configuration = match read_configuration(configuration_file).and_then(|configuration| validate_configuration(&configuration, Some(&context.topology_cache)).map(|_| configuration)) {
+        Ok(configuration) => configuration,
+        Err(err) => {
+            error!("Keeping the current configuration, failed to load {}: {}", configuration_file, err);
+            return;
+        }
+    };
+
+    let current = context.configuration();
+    if configuration.simulated_thermostats != current.simulated_thermostats {
+        warn!("simulated_thermostats changes are applied on restart only");
+        configuration.simulated_thermostats = current.simulated_thermostats.clone();
+    }
+    if configuration.webhook_record_file != current.webhook_record_file {
+        warn!("webhook_record_file changes are applied on restart only");
+        configuration.webhook_record_file = current.webhook_record_file.clone();
+    }
+    if configuration.mqtt_command_workers != current.mqtt_command_workers {
+        warn!("mqtt_command_workers changes are applied on restart only");
+        configuration.mqtt_command_workers = current.mqtt_command_workers;
+    }
+
+    if configuration == current {
+        info!("Configuration unchanged");
+        return;
+    }
+    info!("Applying the new configuration: {:?}", configuration);
+    context.configuration.send_replace(configuration);
+}
+
+/// Reloads the configuration on SIGHUP or when the file changes.
+pub(crate) async fn configuration_watcher(context: &Context, configuration_file: &str, cancellation_token: CancellationToken) {
+    let mut signal = reload_signal();
+    let mut last_modified = modified(configuration_file);
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => { break; },
+            _ = reload_requested(&mut signal) => {
+                info!("SIGHUP received, reloading {}", configuration_file);
+            },
+            _ = tokio::time::sleep(Duration::from_secs(CONFIGURATION_POLL_SECONDS)) => {
+                let current_modified = modified(configuration_file);
+                if current_modified == last_modified {
+                    continue;
+                }
+                info!("{} changed, reloading", configuration_file);
+            }
+        }
+        last_modified = modified(configuration_file);
+        reload_configuration(context, configuration_file);
+    }
+}
+
+/// Waits until the part of the configuration picked by `section` changes.
+pub(crate) async fn section_changed<T: PartialEq>(configuration_updates: &mut watch::Receiver<BridgeConfiguration>, section: impl Fn(&BridgeConfiguration) -> T) {
+    let current = section(&configuration_updates.borrow());
+    loop {
+        if configuration_updates.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+        if section(&configuration_updates.borrow()) != current {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{BridgeConfiguration, CachedTopology, backend::fixtures, topics::TopicTemplates};
+
+    use super::{ConfigurationFormat, configuration_problems, reload_configuration, path_problem};
+
+    const FORMATS: [ConfigurationFormat; 3] = [ConfigurationFormat::Json, ConfigurationFormat::Toml, ConfigurationFormat::Yaml];
+
+    #[test]
+    fn minimal_file_gets_the_same_defaults_in_every_format() {
+        let minimal_files = [
+            (ConfigurationFormat::Json, r#"{ "mqtt_broker": "broker.local" }"#),
+            (ConfigurationFormat::Toml, r#"mqtt_broker = "broker.local""#),
+            (ConfigurationFormat::Yaml, "mqtt_broker: broker.local")
+        ];
+        // Without aliases nor topics the file predates the alias topics
+        let expected = BridgeConfiguration { mqtt_broker: "broker.local".to_string(), mqtt_topics: TopicTemplates::by_id(), ..BridgeConfiguration::default() };
+
+        for (format, content) in minimal_files {
+            assert_eq!(format.parse(content).unwrap(), expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn files_with_aliases_get_the_alias_topics() {
+        let configuration = ConfigurationFormat::Json.parse(r#"{ "mqtt_broker": "broker.local", "aliases": { "module-1": "lounge" } }"#).unwrap();
+        assert_eq!(configuration.mqtt_topics, TopicTemplates::default());
+    }
+
+    #[test]
+    fn aliases_must_be_unique_among_plants_and_among_the_modules_of_a_plant() {
+        let plant = |id: &str, modules: [&str; 2]| serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "modules": modules.iter().map(|module| serde_json::json!({ "device": "chronothermostat", "id": module, "name": module })).collect::<Vec<_>>()
+        })).unwrap();
+        let topology = CachedTopology { plants: vec!(plant("plant-1", ["module-1", "module-2"]), plant("plant-2", ["module-3", "module-4"])) };
+        let aliases = |entries: &[(&str, &str)]| BridgeConfiguration {
+            aliases: entries.iter().map(|(id, alias)| (id.to_string(), alias.to_string())).collect(),
+            ..BridgeConfiguration::default()
+        };
+
+        let same_plant = aliases(&[("module-1", "lounge"), ("module-2", "lounge")]);
+        assert!(configuration_problems(&same_plant, Some(&topology)).iter().any(|problem| problem.contains("lounge")));
+        let two_plants = aliases(&[("plant-1", "home"), ("plant-2", "home")]);
+        assert!(configuration_problems(&two_plants, Some(&topology)).iter().any(|problem| problem.contains("home")));
+
+        let different_plants = aliases(&[("module-1", "lounge"), ("module-3", "lounge")]);
+        assert!(configuration_problems(&different_plants, Some(&topology)).is_empty());
+        let plant_and_module = aliases(&[("plant-1", "lounge"), ("module-1", "lounge")]);
+        assert!(configuration_problems(&plant_and_module, Some(&topology)).is_empty());
+    }
+
+    #[test]
+    fn client_ids_with_whitespace_or_control_characters_are_rejected() {
+        let client_id = |client_id: &str| BridgeConfiguration { mqtt_client_id: Some(client_id.to_string()), ..BridgeConfiguration::default() };
+
+        for invalid in ["", "   ", " bridge", "bridge\t", "bri\ndge", "bridge\u{0}"] {
+            assert_eq!(configuration_problems(&client_id(invalid), None).len(), 1, "{:?}", invalid);
+        }
+        assert!(configuration_problems(&client_id("bridge living-room"), None).is_empty());
+    }
+
+    #[test]
+    fn serialized_configuration_parses_back_in_every_format() {
+        let configuration = BridgeConfiguration {
+            webhook_endpoint: Some("https://bridge.example.com".to_string()),
+            mqtt_password: "secret".to_string(),
+            mqtt_client_id: Some("bridge".to_string()),
+            aliases: BTreeMap::from([("module-1".to_string(), "lounge".to_string())]),
+            ..BridgeConfiguration::default()
+        };
+
+        for format in FORMATS {
+            let content = format.serialize(&configuration).unwrap();
+            assert_eq!(format.parse(&content).unwrap(), configuration, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn deleted_file_keeps_the_current_configuration() {
+        let configuration_file = std::env::temp_dir().join("smarther-bridge-test-reload.json").to_string_lossy().to_string();
+        let configuration = BridgeConfiguration { mqtt_broker: "broker.local".to_string(), ..BridgeConfiguration::default() };
+        std::fs::write(&configuration_file, ConfigurationFormat::Json.serialize(&configuration).unwrap()).unwrap();
+        let context = fixtures::context_with_configuration(fixtures::backend(), configuration.clone());
+
+        std::fs::remove_file(&configuration_file).unwrap();
+        reload_configuration(&context, &configuration_file);
+
+        assert_eq!(context.configuration(), configuration);
+    }
+
+    #[test]
+    fn files_to_create_only_need_a_writable_directory() {
+        let missing_tokens = std::env::temp_dir().join("smarther-bridge-test-missing-tokens.json").to_string_lossy().to_string();
+        assert_eq!(path_problem("tokens file", &missing_tokens), None);
+
+        let missing_directory = std::env::temp_dir().join("smarther-bridge-test-missing").join("tokens.json").to_string_lossy().to_string();
+        assert!(path_problem("tokens file", &missing_directory).unwrap().contains("does not exist"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use actix_web::{web::Data, HttpServer, App};
+use anyhow::anyhow;
+use chrono::Utc;
+use rumqttc::{AsyncClient, MqttOptions, Event, Packet};
+use smarther::{model::ModuleStatus, AuthorizationInfo};
+
+use crate::{BridgeConfiguration, CachedTopology, load_configuration, config::validate_configuration, load_auth_info, refresh_token_if_needed, backend::{ThermostatBackend, SmartherBackend, ThermostatSnapshot}, webhook::{process, webhook_url}};
+
+const DOCTOR_TIMEOUT_SECONDS: u64 = 10;
+
+struct Report {
+    failed: Vec<String>,
+}
+
+impl Report {
+    fn pass(&self, check: &str, detail: impl AsRef<str>) {
+        println!("[PASS] {}: {}", check, detail.as_ref());
+    }
+
+    fn fail(&mut self, check: &str, detail: impl AsRef<str>, hint: &str) {
+        self.failed.push(check.to_string());
+        println!("[FAIL] {}: {}", check, detail.as_ref());
+        println!("       -> {}", hint);
+    }
+
+    fn skip(&self, check: &str, reason: &str) {
+        println!("[SKIP] {}: {}", check, reason);
+    }
+}
+
+fn topology_modules(plants: &CachedTopology) -> BTreeSet<(String, String)> {
+    plants.plants.iter()
+        .flat_map(|plant| plant.modules.iter().map(move |module| (plant.id.clone(), module.id.clone())))
+        .collect()
+}
+
+async fn live_topology(backend: &dyn ThermostatBackend, auth_info: &AuthorizationInfo) -> anyhow::Result<CachedTopology> {
+    let mut plants = vec!();
+    for plant_id in backend.get_plants(auth_info).await? {
+        plants.push(backend.get_topology(auth_info, &plant_id).await?);
+    }
+    Ok(CachedTopology { plants })
+}
+
+async fn check_mqtt(configuration: &BridgeConfiguration) -> anyhow::Result<()> {
+    let mut options = MqttOptions::new("smarther-mqtt-bridge-doctor", configuration.mqtt_broker.clone(), configuration.mqtt_port);
+    options.set_credentials(configuration.mqtt_username.clone(), configuration.mqtt_password.clone());
+    let (client, mut event_loop) = AsyncClient::new(options, 10);
+
+    tokio::time::timeout(Duration::from_secs(DOCTOR_TIMEOUT_SECONDS), async {
+        loop {
+            if let Event::Incoming(Packet::ConnAck(_)) = event_loop.poll().await? {
+                return Ok::<(), anyhow::Error>(());
+            }
+        }
+    }).await.map_err(|_| anyhow!("No answer from the broker within {} seconds", DOCTOR_TIMEOUT_SECONDS))??;
+
+    client.disconnect().await?;
+    Ok(())
+}
+
+async fn check_webhook_route(configuration: &BridgeConfiguration, endpoint: &str, topology: &CachedTopology) -> anyhow::Result<()> {
+    let plant = topology.plants.first().ok_or(anyhow!("No plant to send a synthetic event for"))?;
+    let module = plant.modules.first().ok_or(anyhow!("Plant {} has no modules", plant.id))?;
+    let (sender, receiver) = async_channel::unbounded::<ModuleStatus>();
+    let active_plants = vec!(plant.id.clone());
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new((active_plants.clone(), sender.clone())))
+            .service(process)
+    })
+    .workers(1)
+    .bind((configuration.listen_host.as_str(), configuration.listen_port))
+    .map_err(|err| anyhow!("Failed to bind {}:{}: {}", configuration.listen_host, configuration.listen_port, err))?
+    .run();
+    let server_handle = server.handle();
+
+    let events = serde_json::json!([{
+        "data": ThermostatSnapshot::new(&plant.id, &module.id, Utc::now()).to_json(),
+        "eventTime": Utc::now().to_rfc3339(),
+        "eventType": "doctor",
+        "id": "smarther-mqtt-bridge-doctor",
+        "subject": "smarther-mqtt-bridge-doctor"
+    }]);
+    let self_post = async {
+        let response = reqwest::Client::new()
+            .post(webhook_url(endpoint, &plant.id))
+            .timeout(Duration::from_secs(DOCTOR_TIMEOUT_SECONDS))
+            .json(&events)
+            .send()
+            .await?
+            .error_for_status()?;
+        if response.text().await? != "OK" {
+            return Err(anyhow!("The endpoint answered, but not with this bridge's webhook handler"));
+        }
+        receiver.try_recv().map_err(|_| anyhow!("The synthetic event did not reach the bridge"))?;
+        Ok(())
+    };
+
+    let result = tokio::select! {
+        result = server => result.map_err(anyhow::Error::from).and_then(|_| Err(anyhow!("Webhook listener stopped unexpectedly"))),
+        result = self_post => result
+    };
+    server_handle.stop(false).await;
+    result
+}
+
+fn check_configuration(report: &mut Report, configuration_file: &str, cached_topology: Option<&CachedTopology>) -> BridgeConfiguration {
+    match load_configuration(configuration_file).and_then(|configuration| validate_configuration(&configuration, cached_topology).map(|_| configuration)) {
+        Ok(configuration) => {
+            report.pass("Configuration", format!("{} is valid", configuration_file));
+            configuration
+        },
+        Err(err) => {
+            report.fail("Configuration", err.to_string(), &format!("Fix {} and run doctor again", configuration_file));
+            BridgeConfiguration::default()
+        }
+    }
+}
+
+async fn check_tokens(report: &mut Report, backend: &dyn ThermostatBackend, auth_file: &str) -> Option<AuthorizationInfo> {
+    match load_auth_info(auth_file) {
+        Ok(auth_info) => {
+            let expires_on = auth_info.expires_on;
+            match refresh_token_if_needed(backend, auth_info, auth_file).await {
+                Ok(auth_info) => {
+                    report.pass("Tokens", format!("valid until {}", auth_info.expires_on.to_rfc3339()));
+                    Some(auth_info)
+                },
+                Err(err) => {
+                    report.fail("Tokens", format!("expired on {} and refresh failed: {}", expires_on.to_rfc3339(), err), "Run setup again to obtain new tokens");
+                    None
+                }
+            }
+        },
+        Err(err) => {
+            report.fail("Tokens", format!("cannot read {}: {}", auth_file, err), "Run setup, or start run to use the onboarding page");
+            None
+        }
+    }
+}
+
+async fn check_topology(report: &mut Report, backend: &dyn ThermostatBackend, auth_info: &AuthorizationInfo, cached_topology: Option<&CachedTopology>, topology_file: &str) {
+    match backend.get_plants(auth_info).await {
+        Ok(plants) => report.pass("Smarther API", format!("{} plants visible", plants.len())),
+        Err(err) => report.fail("Smarther API", err.to_string(), "Check the subscription key and the network access to the Smarther API")
+    }
+
+    match (cached_topology, live_topology(backend, auth_info).await) {
+        (Some(cached), Ok(live)) if topology_modules(cached) == topology_modules(&live) => report.pass("Topology cache", format!("{} matches the live topology", topology_file)),
+        (Some(_), Ok(_)) => report.fail("Topology cache", "cached plants and modules differ from the live topology", "Start run with --rediscover-topology"),
+        (None, Ok(_)) => report.fail("Topology cache", format!("{} is missing or unreadable", topology_file), "Start run once or run setup to discover the topology"),
+        (_, Err(err)) => report.fail("Topology cache", format!("cannot fetch the live topology: {}", err), "Check the Smarther API check above")
+    }
+}
+
+pub(crate) async fn doctor(auth_file: &str, topology_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    let mut report = Report { failed: vec!() };
+
+    let cached_topology = std::fs::read_to_string(topology_file).ok()
+        .and_then(|content| serde_json::from_str::<CachedTopology>(&content).ok());
+    let configuration = check_configuration(&mut report, configuration_file, cached_topology.as_ref());
+
+    let backend = SmartherBackend;
+    match check_tokens(&mut report, &backend, auth_file).await {
+        Some(auth_info) => check_topology(&mut report, &backend, &auth_info, cached_topology.as_ref(), topology_file).await,
+        None => {
+            report.skip("Smarther API", "no valid tokens");
+            report.skip("Topology cache", "no valid tokens");
+        }
+    }
+
+    match check_mqtt(&configuration).await {
+        Ok(_) => report.pass("MQTT broker", format!("connected to {}:{}", configuration.mqtt_broker, configuration.mqtt_port)),
+        Err(err) => report.fail("MQTT broker", err.to_string(), "Check mqtt_broker, mqtt_port, mqtt_username and mqtt_password")
+    }
+
+    match std::net::TcpListener::bind((configuration.listen_host.as_str(), configuration.listen_port)) {
+        Ok(_) => report.pass("Webhook listener", format!("{}:{} is free", configuration.listen_host, configuration.listen_port)),
+        Err(err) => report.fail("Webhook listener", format!("cannot bind {}:{}: {}", configuration.listen_host, configuration.listen_port, err), "Stop the running bridge or change listen_host/listen_port")
+    }
+
+    match (&configuration.webhook_endpoint, &cached_topology) {
+        (Some(endpoint), Some(topology)) => match check_webhook_route(&configuration, endpoint, topology).await {
+            Ok(_) => report.pass("Webhook route", format!("{} reaches the bridge", endpoint)),
+            Err(err) => report.fail("Webhook route", err.to_string(), "Make sure webhook_endpoint is publicly reachable and forwarded to listen_host:listen_port")
+        },
+        (None, _) => report.skip("Webhook route", "webhook_endpoint not configured"),
+        (_, None) => report.skip("Webhook route", "no topology to build a synthetic event")
+    }
+
+    if !report.failed.is_empty() {
+        return Err(anyhow!("{} checks failed", report.failed.len()));
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Utc, Duration};
+    use smarther::AuthorizationInfo;
+
+    use crate::{CachedTopology, backend::{FakeBackend, fixtures}};
+
+    use super::{Report, check_configuration, check_tokens, check_topology};
+
+    fn temp_file(name: &str, content: &str) -> String {
+        let file = std::env::temp_dir().join(format!("smarther-bridge-test-doctor-{}", name)).to_string_lossy().to_string();
+        std::fs::write(&file, content).unwrap();
+        file
+    }
+
+    #[test]
+    fn invalid_configuration_fails_the_check() {
+        let mut report = Report { failed: vec!() };
+        check_configuration(&mut report, &temp_file("valid.json", r#"{ "mqtt_broker": "broker.local" }"#), None);
+        assert!(report.failed.is_empty());
+
+        check_configuration(&mut report, &temp_file("unparsable.json", r#"{ "mqtt_broker": "#), None);
+        check_configuration(&mut report, &temp_file("invalid.json", r#"{ "mqtt_broker": "broker.local", "listen_port": 0 }"#), None);
+        assert_eq!(report.failed, ["Configuration", "Configuration"]);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_fail_the_check_when_the_refresh_fails() {
+        let mut report = Report { failed: vec!() };
+        let valid_tokens = temp_file("valid-tokens.json", &serde_json::to_string(&FakeBackend::authorization()).unwrap());
+        assert!(check_tokens(&mut report, &FakeBackend::default(), &valid_tokens).await.is_some());
+        assert!(report.failed.is_empty());
+
+        let mut auth_info: AuthorizationInfo = FakeBackend::authorization();
+        auth_info.expires_on = Utc::now() - Duration::days(1);
+        let expired_tokens = temp_file("expired-tokens.json", &serde_json::to_string(&auth_info).unwrap());
+        let backend = FakeBackend::default().with_refresh_error("invalid_grant");
+        assert!(check_tokens(&mut report, &backend, &expired_tokens).await.is_none());
+        assert_eq!(report.failed, ["Tokens"]);
+    }
+
+    #[tokio::test]
+    async fn cached_topology_is_compared_with_the_live_one() {
+        let mut report = Report { failed: vec!() };
+        let backend = fixtures::backend();
+        let cached_topology = CachedTopology { plants: vec!(fixtures::plant()) };
+        check_topology(&mut report, backend.as_ref(), &FakeBackend::authorization(), Some(&cached_topology), "topology.json").await;
+        assert!(report.failed.is_empty());
+
+        let mut plant = fixtures::plant();
+        plant.modules.clear();
+        let stale_topology = CachedTopology { plants: vec!(plant) };
+        check_topology(&mut report, backend.as_ref(), &FakeBackend::authorization(), Some(&stale_topology), "topology.json").await;
+        assert_eq!(report.failed, ["Topology cache"]);
+    }
+}
@@ -0,0 +1,172 @@
+//! End-to-end tests running the MQTT handler against an embedded broker and a fake backend.
+
+use std::{net::{TcpListener, TcpStream}, time::Duration};
+
+use actix_web::{test, web::Data, App};
+use rumqttc::{AsyncClient, MqttOptions, QoS, Event, Packet, Publish};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use smarther::model::{ModuleStatus, SetStatusRequest};
+use tokio_util::sync::CancellationToken;
+
+use crate::{BridgeConfiguration, backend::fixtures::{self, PLANT_ID, MODULE_ID}, mqtt::{mqtt_handler, status_message}, webhook::process};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+fn start_broker() -> u16 {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config: rumqttd::Config = toml::from_str(&format!(r#"
+        id = 0
+
+        [router]
+        id = 0
+        max_connections = 100
+        max_outgoing_packet_count = 200
+        max_segment_size = 104857600
+        max_segment_count = 10
+
+        [v4.1]
+        name = "v4-1"
+        listen = "127.0.0.1:{port}"
+        next_connection_delay_ms = 1
+
+        [v4.1.connections]
+        connection_timeout_ms = 60000
+        max_payload_size = 20480
+        max_inflight_count = 100
+        dynamic_filters = true
+    "#)).unwrap();
+
+    std::thread::spawn(move || {
+        rumqttd::Broker::new(config).start().unwrap();
+    });
+
+    let deadline = std::time::Instant::now() + TEST_TIMEOUT;
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(std::time::Instant::now() < deadline, "Embedded broker did not start");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    port
+}
+
+fn broker_configuration(port: u16) -> BridgeConfiguration {
+    BridgeConfiguration {
+        mqtt_broker: "127.0.0.1".to_string(),
+        mqtt_port: port,
+        ..BridgeConfiguration::default()
+    }
+}
+
+fn test_client(port: u16, client_id: &str) -> (AsyncClient, UnboundedReceiver<Publish>) {
+    let options = MqttOptions::new(client_id, "127.0.0.1", port);
+    let (client, mut event_loop) = AsyncClient::new(options, 10);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if sender.send(publish).is_err() {
+                        break;
+                    }
+                },
+                Ok(_) => {},
+                Err(_) => tokio::time::sleep(RETRY_INTERVAL).await
+            }
+        }
+    });
+    (client, receiver)
+}
+
+#[actix_web::test]
+async fn set_status_command_reaches_backend() {
+    let port = start_broker();
+    let backend = fixtures::backend();
+    let context = fixtures::context_with_configuration(backend.clone(), broker_configuration(port));
+    let cancellation_token = CancellationToken::new();
+    let (client, _) = test_client(port, "test-commander");
+
+    let topic = format!("smarther/{}/{}/set_status", PLANT_ID, MODULE_ID);
+    let payload = serde_json::json!({
+        "function": "HEATING",
+        "mode": "MANUAL",
+        "setPoint": { "value": "21.5", "unit": "C" },
+        "programs": [{ "number": 0 }]
+    }).to_string();
+
+    let (_, command) = tokio::join!(
+        mqtt_handler(&context, cancellation_token.clone()),
+        async {
+            // Keep publishing until the bridge subscription is in place
+            let command = tokio::time::timeout(TEST_TIMEOUT, async {
+                loop {
+                    client.publish(&topic, QoS::AtLeastOnce, false, payload.clone()).await.unwrap();
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    if let Some(command) = backend.take_commands().pop() {
+                        return command;
+                    }
+                }
+            }).await;
+            context.status_updates.0.close();
+            cancellation_token.cancel();
+            command
+        }
+    );
+
+    let command = command.expect("No command reached the backend");
+    assert_eq!(command.plant_id, PLANT_ID);
+    assert_eq!(command.module_id, MODULE_ID);
+    let expected_request: SetStatusRequest = serde_json::from_str(&payload).unwrap();
+    assert_eq!(format!("{:?}", command.request), format!("{:?}", expected_request));
+}
+
+#[actix_web::test]
+async fn webhook_event_is_published_on_status_topic() {
+    let port = start_broker();
+    let context = fixtures::context_with_configuration(fixtures::backend(), broker_configuration(port));
+    let cancellation_token = CancellationToken::new();
+    let (client, mut publishes) = test_client(port, "test-listener");
+    client.subscribe("smarther/+/+/status", QoS::AtLeastOnce).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new((vec!(PLANT_ID.to_string()), context.status_updates.0.clone())))
+            .service(process)
+    ).await;
+    let events = serde_json::json!([{
+        "data": fixtures::module_status_json(PLANT_ID, MODULE_ID, 22.5),
+        "eventTime": "2023-04-10T10:00:00Z",
+        "eventType": "cloud2cloud",
+        "id": "event-1",
+        "subject": "smarther"
+    }]);
+
+    let (_, publish) = tokio::join!(
+        mqtt_handler(&context, cancellation_token.clone()),
+        async {
+            // Keep sending events until the listener subscription is in place
+            let publish = tokio::time::timeout(TEST_TIMEOUT, async {
+                loop {
+                    let request = test::TestRequest::post()
+                        .uri(&format!("/smarther_bridge/{}", PLANT_ID))
+                        .set_json(&events)
+                        .to_request();
+                    assert_eq!(test::call_and_read_body(&app, request).await, "OK");
+
+                    if let Ok(Some(publish)) = tokio::time::timeout(RETRY_INTERVAL, publishes.recv()).await {
+                        return publish;
+                    }
+                }
+            }).await;
+            context.status_updates.0.close();
+            cancellation_token.cancel();
+            publish
+        }
+    );
+
+    let publish = publish.expect("No status was published");
+    let expected_status: ModuleStatus = serde_json::from_value(events[0]["data"].clone()).unwrap();
+    let (expected_topic, expected_summary) = status_message(&context, &expected_status.chronothermostats[0]).unwrap();
+    let summary: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(publish.topic, expected_topic);
+    assert_eq!(summary, serde_json::to_value(expected_summary).unwrap());
+}
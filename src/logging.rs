@@ -0,0 +1,91 @@
+use std::{fmt, sync::atomic::{AtomicU64, Ordering}};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing_subscriber::{EnvFilter, fmt::format::FmtSpan};
+
+const REDACTED: &str = "***";
+const SENSITIVE_KEYS: [&str; 6] = ["mqtt_password", "access_token", "refresh_token", "client_secret", "subscription_key", "password"];
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum LogFormat {
+    Text,
+    Json
+}
+
+/// Installs the subscriber, `log` records from dependencies are forwarded to it.
+pub(crate) fn init(format: LogFormat) {
+    // Same default as env_logger, RUST_LOG still selects the levels
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    // Closing a span logs its fields, including the outcome recorded once the work is done
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init()
+    }
+}
+
+/// Identifier correlating the log lines of a webhook request, MQTT command or API call.
+pub(crate) fn request_id() -> String {
+    format!("{}-{}", std::process::id(), NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SENSITIVE_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        },
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Displays a configuration or authorization object with passwords and tokens masked.
+pub(crate) struct Redacted<'a, T: Serialize>(pub &'a T);
+
+impl<T: Serialize> fmt::Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut value = serde_json::to_value(self.0).map_err(|_| fmt::Error)?;
+        redact(&mut value);
+        write!(f, "{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BridgeConfiguration, backend::FakeBackend};
+
+    use super::Redacted;
+
+    #[test]
+    fn configuration_password_is_redacted() {
+        let configuration = BridgeConfiguration {
+            mqtt_password: "secret-password".to_string(),
+            ..BridgeConfiguration::default()
+        };
+
+        let logged = format!("{:?}", configuration);
+        assert!(!logged.contains("secret-password"));
+        assert!(logged.contains("mqtt_password"));
+    }
+
+    #[test]
+    fn tokens_are_redacted() {
+        let logged: serde_json::Value = serde_json::from_str(&Redacted(&FakeBackend::authorization()).to_string()).unwrap();
+        assert_eq!(logged["access_token"], "***");
+        assert_eq!(logged["refresh_token"], "***");
+        assert_eq!(logged["client_secret"], "***");
+        assert_eq!(logged["client_id"], "fake");
+    }
+}
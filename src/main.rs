@@ -1,21 +1,52 @@
 #[macro_use] extern crate serde;
-use std::{env::{self, current_dir}, cell::RefCell};
+use std::{env::{self, current_dir}, cell::{Cell, RefCell}, collections::BTreeMap, fmt, rc::Rc, time::Duration};
 
 use anyhow::anyhow;
-use clap::{Subcommand, Parser, Args};
+use chrono::{DateTime, Utc};
+use clap::{Subcommand, Parser, Args, ValueEnum, ArgGroup};
 use async_channel::{Receiver, Sender};
-use log::info;
-use smarther::{model::{PlantDetail, ModuleStatus}, AuthorizationInfo, SmartherApi, states::{Unauthorized}};
+use tracing::{info, error, warn, debug};
+use smarther::{model::{PlantDetail, ModuleStatus}, AuthorizationInfo, SmartherApi};
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
 
-use crate::{token_watchdog::token_refresher, mqtt::mqtt_handler, webhook::webhook_handler};
+use crate::{token_watchdog::{token_refresher, AuthState}, mqtt::mqtt_handler, webhook::webhook_handler, oauth::OAuthClient, config::{ConfigurationFormat, configuration_watcher, read_configuration}, onboarding::onboarding_handler, backend::{ThermostatBackend, SmartherBackend, FakeBackend}, simulation::{SimulatedThermostat, SimulatedBackend, simulation_handler}, systemd::{ServiceHealth, systemd_notifier, notify_startup_phase}, logging::{LogFormat, Redacted}, topics::TopicTemplates};
 
 mod token_watchdog;
 mod mqtt;
 mod webhook;
+mod oauth;
+mod onboarding;
+mod auth;
+mod backend;
+mod mock_cloud;
+mod recording;
+mod simulation;
+mod cli;
+mod doctor;
+mod config;
+mod systemd;
+mod logging;
+mod topics;
+#[cfg(test)]
+mod integration_tests;
 
 #[derive(Parser)]
 struct SmartherBridgeArgs {
+    /// Directory holding the bridge files, defaults to SMARTHER_CONFIG_DIR or the current directory
+    #[clap(long, global = true)]
+    config_dir: Option<String>,
+    /// Configuration file (.json, .toml, .yaml or .yml), defaults to the first existing <config-dir>/configuration.{json,toml,yaml,yml}
+    #[clap(long, global = true)]
+    config: Option<String>,
+    /// Tokens file, defaults to <config-dir>/tokens.json
+    #[clap(long, global = true)]
+    tokens: Option<String>,
+    /// Topology cache, defaults to <config-dir>/plant_topology.json
+    #[clap(long, global = true)]
+    topology: Option<String>,
+    #[clap(long, global = true, value_enum, env = "SMARTHER_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
     #[clap(subcommand)]
     command: Commands
 } 
@@ -26,7 +57,173 @@ enum Commands {
         #[clap(flatten)]
         setup_args: SetupArgs
     },
-    Run
+    Run {
+        #[clap(flatten)]
+        run_args: RunArgs
+    },
+    Auth {
+        #[clap(subcommand)]
+        command: AuthCommands
+    },
+    /// Print the current status of the thermostats
+    Status {
+        #[clap(flatten)]
+        status_args: StatusArgs
+    },
+    /// Change the status of a thermostat
+    Set {
+        #[clap(flatten)]
+        set_args: SetArgs
+    },
+    /// Print the cached plant topology
+    Topology,
+    /// Inspect the configuration file
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommands
+    },
+    /// Check configuration, tokens, Smarther API, MQTT broker and webhook reachability
+    Doctor,
+    /// Inspect and manage webhook subscriptions
+    Webhooks {
+        #[clap(subcommand)]
+        command: WebhookCommands
+    },
+    /// Publish webhook events recorded through webhook_record_file to MQTT
+    Replay {
+        #[clap(flatten)]
+        replay_args: ReplayArgs
+    },
+    /// Serve a local mock of the Smarther cloud, point setup's --base-uri and --auth-uri at it and pass --headless
+    MockCloud {
+        #[clap(flatten)]
+        mock_cloud_args: MockCloudArgs
+    }
+}
+
+#[derive(Args)]
+struct StatusArgs {
+    #[clap(long)]
+    plant: Option<String>,
+    #[clap(long)]
+    module: Option<String>,
+    #[clap(long, value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Json,
+    Table
+}
+
+#[derive(Args)]
+struct SetArgs {
+    /// Plant id, can be omitted when there is a single plant
+    #[clap(long)]
+    plant: Option<String>,
+    /// Module id, can be omitted when the plant has a single module
+    #[clap(long)]
+    module: Option<String>,
+    #[clap(long, value_enum)]
+    mode: SetMode,
+    #[clap(long, default_value = "HEATING")]
+    function: String,
+    /// Set point in Celsius, required by the manual mode
+    #[clap(long)]
+    temperature: Option<f64>,
+    /// Program number, used by the automatic mode
+    #[clap(long)]
+    program: Option<u32>,
+    /// Minutes before the thermostat goes back to its program, manual and boost only
+    #[clap(long)]
+    duration: Option<i64>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SetMode {
+    Automatic,
+    Manual,
+    Boost,
+    Off,
+    Protection
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Report unknown keys, invalid values and unreachable paths
+    Validate,
+    /// Write the configuration in another format
+    Convert {
+        #[clap(long, value_enum)]
+        to: ConfigurationFormat,
+        /// Destination file, defaults to the configuration file with the new extension
+        #[clap(long)]
+        output: Option<String>,
+    }
+}
+
+#[derive(Subcommand)]
+enum WebhookCommands {
+    /// List every subscription of the account
+    List,
+    /// Register this bridge's webhook for every plant in the topology
+    Register {
+        #[clap(long)]
+        plant: Option<String>,
+        /// Endpoint to register instead of the configured webhook_endpoint
+        #[clap(long)]
+        endpoint: Option<String>,
+    },
+    /// Unregister subscriptions
+    #[clap(group(ArgGroup::new("target").required(true).args(["all", "id"])))]
+    Unregister {
+        /// Unregister every subscription, including the ones not created by this bridge
+        #[clap(long)]
+        all: bool,
+        #[clap(long)]
+        id: Option<String>,
+    }
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// JSON-lines file written by the webhook recorder
+    file: String,
+    /// Wait between events as long as it happened when they were recorded
+    #[clap(long)]
+    original_timing: bool,
+}
+
+#[derive(Args)]
+struct MockCloudArgs {
+    /// Topology served by the mock, defaults to the bridge topology cache
+    #[clap(long)]
+    fixture: Option<String>,
+    #[clap(long, default_value = "localhost")]
+    host: String,
+    #[clap(long, default_value_t = 8090)]
+    port: u16,
+}
+
+#[derive(Subcommand)]
+enum AuthCommands {
+    /// Show token expiry and whether a refresh is needed
+    Status,
+    /// Refresh the access token if needed
+    Refresh,
+    /// Unregister this bridge's webhooks and delete the stored tokens
+    Logout
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Discover plants and modules again instead of using the cached topology
+    #[clap(long)]
+    rediscover_topology: bool,
+    /// Use the virtual thermostats from simulated_thermostats instead of the Smarther cloud
+    #[clap(long)]
+    simulate: bool,
 }
 
 #[derive(Args)]
@@ -39,6 +236,18 @@ struct SetupArgs {
     subkey: Option<String>,
     #[clap(long)]
     base_uri: Option<String>,
+    /// Print the authorization URL and read the redirect URL (or code) from stdin
+    #[clap(long)]
+    headless: bool,
+    /// Import an existing refresh token instead of running the OAuth flow
+    #[clap(long)]
+    refresh_token: Option<String>,
+    /// Redirect URI registered for the application, defaults to http://<listen_host>:<listen_port>
+    #[clap(long)]
+    redirect_uri: Option<String>,
+    /// OAuth server, only used by --headless
+    #[clap(long, default_value = oauth::DEFAULT_AUTH_URI)]
+    auth_uri: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
@@ -47,31 +256,88 @@ struct CachedTopology {
 }
 
 struct Context {
-    configuration: BridgeConfiguration,
+    configuration: watch::Sender<BridgeConfiguration>,
     topology_cache: CachedTopology,
     auth_info: RefCell<AuthorizationInfo>,
     reset_refresh_watchdog: (Sender<()>, Receiver<()>),
     status_updates: (Sender<ModuleStatus>, Receiver<ModuleStatus>),
+    auth_state: RefCell<AuthState>,
+    auth_updates: (Sender<AuthState>, Receiver<AuthState>),
     auth_file: String,
+    backend: Rc<dyn ThermostatBackend>,
+    health: ServiceHealth,
+    shutdown_deadline: Cell<Option<tokio::time::Instant>>,
+    mqtt_offline: CancellationToken,
 }
 
 impl Context {
+    pub fn new(configuration: BridgeConfiguration, topology_cache: CachedTopology, auth_info: AuthorizationInfo, auth_file: String, backend: Rc<dyn ThermostatBackend>) -> Self {
+        Self {
+            configuration: watch::channel(configuration).0,
+            topology_cache,
+            auth_state: RefCell::new(AuthState::new(&auth_info, tokens_stored_at(&auth_file))),
+            auth_info: RefCell::new(auth_info),
+            reset_refresh_watchdog: async_channel::bounded(1),
+            status_updates: async_channel::unbounded(),
+            auth_updates: async_channel::unbounded(),
+            auth_file,
+            backend,
+            health: ServiceHealth::new(),
+            shutdown_deadline: Cell::new(None),
+            mqtt_offline: CancellationToken::new()
+        }
+    }
+
+    /// Snapshot of the current configuration, which can change on reload.
+    pub fn configuration(&self) -> BridgeConfiguration {
+        self.configuration.borrow().clone()
+    }
+
+    /// Deadline shared by all the shutdown steps, starting with the first step that asks for it.
+    pub fn shutdown_deadline(&self) -> tokio::time::Instant {
+        let deadline = self.shutdown_deadline.get()
+            .unwrap_or_else(|| tokio::time::Instant::now() + self.configuration().shutdown_timeout());
+        self.shutdown_deadline.set(Some(deadline));
+        deadline
+    }
+
     pub async fn refresh_token_if_needed(&self) -> anyhow::Result<()> {
         let auth_info = self.auth_info.borrow().clone();
-        let client = SmartherApi::default();
-        let refreshed = refresh_token_if_needed(&client, auth_info, &self.auth_file).await?;
+        // Failures are left to the token refresher, the next command simply tries again
+        let refreshed = refresh_token_if_needed(self.backend.as_ref(), auth_info.clone(), &self.auth_file).await?;
+        self.update_auth_state(|state| state.record_success(&auth_info, &refreshed)).await;
+        let token_changed = refreshed.expires_on != auth_info.expires_on;
         self.auth_info.replace(refreshed);
-        self.reset_refresh_watchdog.0.send(()).await?;
+        // Not awaited, the token refresher does not run in every mode and a pending reset is as good as a new one
+        if token_changed {
+            let _ = self.reset_refresh_watchdog.0.try_send(());
+        }
         Ok(())
     }
 
+    /// Applies `update` and hands the state to the MQTT handler when it changed.
+    pub async fn update_auth_state(&self, update: impl FnOnce(&mut AuthState)) {
+        let auth_state = {
+            let mut auth_state = self.auth_state.borrow_mut();
+            let previous = auth_state.clone();
+            update(&mut auth_state);
+            if *auth_state == previous {
+                return;
+            }
+            auth_state.clone()
+        };
+        if self.auth_updates.0.send(auth_state).await.is_err() {
+            error!("Failed to send auth state to MQTT handler");
+        }
+    }
+
     async fn wait_token_reset(&self) -> anyhow::Result<()> {
         self.reset_refresh_watchdog.1.recv().await?;
         Ok(())
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[derive(Deserialize, Serialize, PartialEq, Clone)]
 struct BridgeConfiguration {
     #[serde(skip_serializing_if = "Option::is_none")]
     webhook_endpoint: Option<String>,
@@ -89,6 +355,36 @@ struct BridgeConfiguration {
     listen_port: u16,
     #[serde(default = "BridgeConfiguration::default_listen_host")]
     listen_host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook_record_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    simulated_thermostats: Vec<SimulatedThermostat>,
+    #[serde(default = "BridgeConfiguration::default_shutdown_timeout_seconds")]
+    shutdown_timeout_seconds: u64,
+    #[serde(default = "BridgeConfiguration::default_mqtt_command_workers")]
+    mqtt_command_workers: usize,
+    #[serde(default = "BridgeConfiguration::default_mqtt_clean_session")]
+    mqtt_clean_session: bool,
+    #[serde(default = "BridgeConfiguration::default_mqtt_reconnect_max_delay_seconds")]
+    mqtt_reconnect_max_delay_seconds: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mqtt_client_id: Option<String>,
+    #[serde(default = "BridgeConfiguration::default_mqtt_keep_alive_seconds")]
+    mqtt_keep_alive_seconds: u64,
+    #[serde(default = "BridgeConfiguration::default_mqtt_inflight")]
+    mqtt_inflight: u16,
+    #[serde(default = "BridgeConfiguration::default_mqtt_channel_capacity")]
+    mqtt_channel_capacity: usize,
+    #[serde(default)]
+    mqtt_topics: TopicTemplates,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    aliases: BTreeMap<String, String>,
+}
+
+impl fmt::Debug for BridgeConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BridgeConfiguration {}", Redacted(self))
+    }
 }
 
 impl Default for BridgeConfiguration {
@@ -101,7 +397,19 @@ impl Default for BridgeConfiguration {
             mqtt_username: BridgeConfiguration::default_mqtt_username(), 
             mqtt_password: BridgeConfiguration::default_mqtt_password(),
             listen_port: BridgeConfiguration::default_listen_port(),
-            listen_host: BridgeConfiguration::default_listen_host()
+            listen_host: BridgeConfiguration::default_listen_host(),
+            webhook_record_file: None,
+            simulated_thermostats: vec!(),
+            shutdown_timeout_seconds: BridgeConfiguration::default_shutdown_timeout_seconds(),
+            mqtt_command_workers: BridgeConfiguration::default_mqtt_command_workers(),
+            mqtt_clean_session: BridgeConfiguration::default_mqtt_clean_session(),
+            mqtt_reconnect_max_delay_seconds: BridgeConfiguration::default_mqtt_reconnect_max_delay_seconds(),
+            mqtt_client_id: None,
+            mqtt_keep_alive_seconds: BridgeConfiguration::default_mqtt_keep_alive_seconds(),
+            mqtt_inflight: BridgeConfiguration::default_mqtt_inflight(),
+            mqtt_channel_capacity: BridgeConfiguration::default_mqtt_channel_capacity(),
+            mqtt_topics: TopicTemplates::default(),
+            aliases: BTreeMap::new()
         }
     }
 }
@@ -134,6 +442,45 @@ impl BridgeConfiguration {
     fn default_listen_host() -> String {
         "localhost".to_string()
     }
+
+    fn default_shutdown_timeout_seconds() -> u64 {
+        10
+    }
+
+    fn default_mqtt_command_workers() -> usize {
+        4
+    }
+
+    fn default_mqtt_clean_session() -> bool {
+        true
+    }
+
+    fn default_mqtt_reconnect_max_delay_seconds() -> u64 {
+        60
+    }
+
+    fn default_mqtt_keep_alive_seconds() -> u64 {
+        60
+    }
+
+    fn default_mqtt_inflight() -> u16 {
+        100
+    }
+
+    fn default_mqtt_channel_capacity() -> usize {
+        100
+    }
+
+    fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_seconds)
+    }
+}
+
+fn load_configuration(configuration_file: &str) -> anyhow::Result<BridgeConfiguration> {
+    match read_configuration(configuration_file) {
+        Err(err) if is_not_found(&err) => Ok(BridgeConfiguration::default()),
+        result => result
+    }
 }
 
 fn load_auth_info(auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
@@ -142,12 +489,29 @@ fn load_auth_info(auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
     Ok(auth_info)
 }
 
-async fn refresh_token_if_needed(client: &SmartherApi<Unauthorized>, auth_info: AuthorizationInfo, auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
+/// When the tokens file was last written, now when that is unknown.
+fn tokens_stored_at(auth_file: &str) -> DateTime<Utc> {
+    std::fs::metadata(auth_file)
+        .and_then(|metadata| metadata.modified())
+        .map(DateTime::from)
+        .unwrap_or_else(|_| Utc::now())
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>().is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+}
+
+async fn refresh_token(backend: &dyn ThermostatBackend, auth_info: &AuthorizationInfo, auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
+    let refreshed_auth_info = backend.refresh_token(auth_info).await?;
+    debug!("Refreshed tokens: {}", Redacted(&refreshed_auth_info));
+    let refreshed_auth_info_json = serde_json::to_string_pretty(&refreshed_auth_info)?;
+    std::fs::write(auth_file, refreshed_auth_info_json)?;
+    Ok(refreshed_auth_info)
+}
+
+async fn refresh_token_if_needed(backend: &dyn ThermostatBackend, auth_info: AuthorizationInfo, auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
     if auth_info.is_refresh_needed() {
-        let refreshed_auth_info = client.refresh_token(&auth_info).await?;
-        let refreshed_auth_info_json = serde_json::to_string_pretty(&refreshed_auth_info)?;
-        std::fs::write(auth_file, refreshed_auth_info_json)?;
-        return Ok(refreshed_auth_info);
+        return refresh_token(backend, &auth_info, auth_file).await;
     }
 
     Ok(auth_info)
@@ -155,20 +519,49 @@ async fn refresh_token_if_needed(client: &SmartherApi<Unauthorized>, auth_info:
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
     let args = SmartherBridgeArgs::parse();
-    let config_dir = env::var("SMARTHER_CONFIG_DIR").unwrap_or_else(|_| current_dir().unwrap().to_string_lossy().into());
-    let auth_file = format!("{}/tokens.json", config_dir);
-    let plant_topology_file = format!("{}/plant_topology.json", config_dir);
-    let configuration_file = format!("{}/configuration.json", config_dir);
+    logging::init(args.log_format);
+    let config_dir = args.config_dir.clone()
+        .or_else(|| env::var("SMARTHER_CONFIG_DIR").ok())
+        .unwrap_or_else(|| current_dir().unwrap().to_string_lossy().into());
+    let auth_file = args.tokens.clone().unwrap_or_else(|| format!("{}/tokens.json", config_dir));
+    let plant_topology_file = args.topology.clone().unwrap_or_else(|| format!("{}/plant_topology.json", config_dir));
+    let configuration_file = args.config.clone().unwrap_or_else(|| config::default_configuration_file(&config_dir));
 
     match &args.command {
         Commands::Setup { setup_args } => {
             setup(setup_args, &auth_file, &plant_topology_file, &configuration_file).await?;
             
         },
-        _ => {
-            run(auth_file, plant_topology_file, configuration_file).await?;
+        Commands::Run { run_args } => {
+            run(run_args, auth_file, plant_topology_file, configuration_file).await?;
+        },
+        Commands::Auth { command } => {
+            auth::auth_command(command, &auth_file, &configuration_file).await?;
+        },
+        Commands::Status { status_args } => {
+            cli::status(status_args, &auth_file, &plant_topology_file).await?;
+        },
+        Commands::Set { set_args } => {
+            cli::set(set_args, &auth_file, &plant_topology_file).await?;
+        },
+        Commands::Topology => {
+            cli::topology(&auth_file, &plant_topology_file).await?;
+        },
+        Commands::Config { command } => {
+            config::config_command(command, &auth_file, &plant_topology_file, &configuration_file)?;
+        },
+        Commands::Doctor => {
+            doctor::doctor(&auth_file, &plant_topology_file, &configuration_file).await?;
+        },
+        Commands::Webhooks { command } => {
+            cli::webhooks(command, &auth_file, &plant_topology_file, &configuration_file).await?;
+        },
+        Commands::Replay { replay_args } => {
+            recording::replay(replay_args, &plant_topology_file, &configuration_file).await?;
+        },
+        Commands::MockCloud { mock_cloud_args } => {
+            mock_cloud::mock_cloud(mock_cloud_args, &plant_topology_file).await?;
         }
     }
 
@@ -178,18 +571,35 @@ async fn main() -> anyhow::Result<()> {
 async fn setup(setup_args: &SetupArgs, auth_file: &str, topology_file: &str, configuration_file: &str) -> anyhow::Result<()> {
     let client = SmartherApi::default();
 
-    let configuration = if let Ok(configuration_content) = std::fs::read_to_string(configuration_file) {
-        serde_json::from_str(&configuration_content)?
-    } else {
-        BridgeConfiguration::default()
-    };
-
-    let auth_info = if let Ok(auth_info) = load_auth_info(auth_file) {
-        auth_info
-    } else { 
-        match setup_args {
-            SetupArgs{ client_id: Some(client_id), client_secret: Some(client_secret), subkey: Some(subkey), base_uri } => {
-                let auth_info = client.get_oauth_access_code(client_id, client_secret, base_uri.as_deref(), subkey, (&configuration.listen_host, configuration.listen_port)).await?;
+    let configuration = load_configuration(configuration_file)?;
+
+    // Importing a token or authorizing again replaces the stored tokens, otherwise they are reused
+    let reauthorize = setup_args.refresh_token.is_some() || setup_args.headless;
+    let auth_info = match load_auth_info(auth_file) {
+        Ok(auth_info) if !reauthorize => auth_info,
+        existing => match setup_args {
+            SetupArgs{ client_id: Some(client_id), client_secret: Some(client_secret), subkey: Some(subkey), base_uri, headless, refresh_token, redirect_uri, auth_uri } => {
+                // The browser flow of the smarther crate always talks to the Legrand login
+                if auth_uri != oauth::DEFAULT_AUTH_URI && !*headless {
+                    return Err(anyhow!("--auth-uri is only used by --headless, add it to authorize against {}", auth_uri));
+                }
+                let redirect_uri = redirect_uri.clone().unwrap_or_else(|| format!("http://{}:{}", configuration.listen_host, configuration.listen_port));
+                let oauth_client = OAuthClient {
+                    client_id,
+                    client_secret,
+                    subscription_key: subkey,
+                    base_uri: base_uri.as_deref(),
+                    auth_uri,
+                    redirect_uri: &redirect_uri
+                };
+                let auth_info = match refresh_token {
+                    Some(refresh_token) => oauth_client.import_refresh_token(refresh_token)?,
+                    None if *headless => oauth_client.headless_access_code().await?,
+                    None => client.get_oauth_access_code(client_id, client_secret, base_uri.as_deref(), subkey, (&configuration.listen_host, configuration.listen_port)).await?
+                };
+                if existing.is_ok() {
+                    info!("Replacing the tokens stored in {}", auth_file);
+                }
                 let auth_info_json = serde_json::to_string_pretty(&auth_info)?;
                 std::fs::write(auth_file, auth_info_json)?;
                 auth_info
@@ -200,61 +610,140 @@ async fn setup(setup_args: &SetupArgs, auth_file: &str, topology_file: &str, con
         }
     };
 
-    let auth_info = refresh_token_if_needed(&client, auth_info, auth_file).await?;
-    let client = client.with_authorization(auth_info)?;
+    let backend = SmartherBackend;
+    let auth_info = refresh_token_if_needed(&backend, auth_info, auth_file).await?;
+    discover_topology(&backend, &auth_info, topology_file).await?;
+    info!("Setup completed");
+
+    Ok(())
+}
 
+async fn discover_topology(backend: &dyn ThermostatBackend, auth_info: &AuthorizationInfo, topology_file: &str) -> anyhow::Result<CachedTopology> {
     let mut topology = vec!();
-    let plants = client.get_plants().await?;
-    for plant in &plants.plants {
-        let plant_detail = client.get_topology(&plant.id).await?;
-        topology.push(plant_detail.plant);
+    for plant_id in backend.get_plants(auth_info).await? {
+        topology.push(backend.get_topology(auth_info, &plant_id).await?);
     }
-    let topology_json = serde_json::to_string_pretty(&CachedTopology { plants: topology })?;
+    let topology = CachedTopology { plants: topology };
+    let topology_json = serde_json::to_string_pretty(&topology)?;
     std::fs::write(topology_file, topology_json)?;
-    info!("Setup completed");
+    Ok(topology)
+}
 
-    Ok(())
+async fn load_topology(rediscover: bool, backend: &dyn ThermostatBackend, auth_info: &AuthorizationInfo, topology_file: &str) -> anyhow::Result<CachedTopology> {
+    if rediscover {
+        info!("Rediscovering plant topology as requested");
+        return discover_topology(backend, auth_info, topology_file).await;
+    }
+
+    let cached_topology = std::fs::read_to_string(topology_file)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(serde_json::from_str::<CachedTopology>(&content)?));
+
+    match cached_topology {
+        Ok(topology) => Ok(topology),
+        Err(err) => {
+            warn!("Topology cache {} is missing or unreadable ({}), discovering plants and modules", topology_file, err);
+            let topology = discover_topology(backend, auth_info, topology_file).await?;
+            info!("Discovered {} plants, topology cached in {}", topology.plants.len(), topology_file);
+            Ok(topology)
+        }
+    }
 }
 
-async fn run(auth_file: String, topology_file: String, configuration_file: String) -> anyhow::Result<()> {
-    let auth_info = RefCell::new(load_auth_info(&auth_file)?);
-    let topology_cache = std::fs::read_to_string(&topology_file)?;
-    let topology_cache: CachedTopology = serde_json::from_str(&topology_cache)?;
+async fn run(run_args: &RunArgs, auth_file: String, topology_file: String, configuration_file: String) -> anyhow::Result<()> {
+    let configuration = load_configuration(&configuration_file)?;
+    config::validate_configuration(&configuration, None)?;
+    info!("Loaded {}: {:?}", configuration_file, configuration);
+
+    // Write the defaults for a first start, an existing file is never rewritten as that would drop its comments
+    if !std::path::Path::new(&configuration_file).exists() {
+        let configuration_content = ConfigurationFormat::from_path(&configuration_file)?.serialize(&configuration)?;
+        std::fs::write(&configuration_file, configuration_content)?;
+    }
 
-    let configuration = if let Ok(configuration_content) = std::fs::read_to_string(&configuration_file) {
-        serde_json::from_str(&configuration_content)?
-    } else {
-        BridgeConfiguration::default()
+    if run_args.simulate {
+        return run_simulation(configuration, auth_file, configuration_file).await;
+    }
+
+    let backend = SmartherBackend;
+    let (auth_info, topology_cache) = match load_auth_info(&auth_file) {
+        Ok(auth_info) => notify_startup_phase("Loading plant topology", async {
+            let auth_info = refresh_token_if_needed(&backend, auth_info, &auth_file).await?;
+            let topology_cache = load_topology(run_args.rediscover_topology, &backend, &auth_info, &topology_file).await?;
+            anyhow::Ok((auth_info, topology_cache))
+        }).await?,
+        // Unconfigured bridge, collect credentials through the onboarding page
+        Err(err) if is_not_found(&err) => notify_startup_phase("Waiting for onboarding through the setup page", async {
+            let auth_info = onboarding_handler(&configuration, &auth_file).await?;
+            let topology_cache = discover_topology(&backend, &auth_info, &topology_file).await?;
+            anyhow::Ok((auth_info, topology_cache))
+        }).await?,
+        // A corrupt or unreadable tokens file must not be overwritten by onboarding
+        Err(err) => return Err(anyhow!("Failed to load tokens from {}: {}", auth_file, err))
     };
 
-    //Save configuration
-    let configuration_json = serde_json::to_string_pretty(&configuration)?;
-    std::fs::write(configuration_file, configuration_json)?;
+    config::validate_configuration(&configuration, Some(&topology_cache))?;
 
     //Create context and run
-    let context = Context {
-        configuration,
-        topology_cache,
-        auth_info,
-        reset_refresh_watchdog: async_channel::bounded(1),
-        status_updates: async_channel::unbounded(),
-        auth_file
-    };
+    let context = Context::new(configuration, topology_cache, auth_info, auth_file, Rc::new(backend));
+
+    let cancellation_token = CancellationToken::new();
+    tokio::join!(
+        interrupt_handler(cancellation_token.clone()),
+        configuration_watcher(&context, &configuration_file, cancellation_token.clone()),
+        webhook_handler(&context, cancellation_token.clone()),
+        mqtt_handler(&context, cancellation_token.clone()),
+        token_refresher(&context, cancellation_token.clone()),
+        systemd_notifier(&context, cancellation_token.clone())
+    );
+
+    Ok(())
+}
+
+async fn run_simulation(configuration: BridgeConfiguration, auth_file: String, configuration_file: String) -> anyhow::Result<()> {
+    let backend = Rc::new(SimulatedBackend::new(&configuration.simulated_thermostats)?);
+    let topology_cache = CachedTopology { plants: backend.plants()? };
+    config::validate_configuration(&configuration, Some(&topology_cache))?;
+    let context = Context::new(configuration, topology_cache, FakeBackend::authorization(), auth_file, backend.clone());
 
     let cancellation_token = CancellationToken::new();
     tokio::join!(
         interrupt_handler(cancellation_token.clone()),
+        configuration_watcher(&context, &configuration_file, cancellation_token.clone()),
         webhook_handler(&context, cancellation_token.clone()),
         mqtt_handler(&context, cancellation_token.clone()),
-        token_refresher(&context, cancellation_token.clone())
+        simulation_handler(&context, &backend, cancellation_token.clone()),
+        systemd_notifier(&context, cancellation_token.clone())
     );
 
     Ok(())
 }
 
+#[cfg(unix)]
+async fn terminate_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => { terminate.recv().await; },
+        Err(err) => {
+            error!("Failed to listen for SIGTERM: {}", err);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate_signal() {
+    std::future::pending::<()>().await;
+}
+
 async fn interrupt_handler(cancellation_token: CancellationToken) {
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
+            info!("SIGINT received, shutting down");
+            cancellation_token.cancel();
+        },
+        _ = terminate_signal() => {
+            info!("SIGTERM received, shutting down");
             cancellation_token.cancel();
         },
         _ = cancellation_token.cancelled() => {}
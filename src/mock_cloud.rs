@@ -0,0 +1,180 @@
+use actix_web::{get, post, delete, web::{Data, self}, HttpServer, App, HttpResponse, middleware::Logger, http::header};
+use chrono::Utc;
+use tracing::{info, error};
+use smarther::model::SetStatusRequest;
+
+use crate::{CachedTopology, MockCloudArgs, backend::{FakeBackend, ThermostatSnapshot}};
+
+const MODULE_PATH: &str = "/chronothermostat/thermoregulation/addressLocation/plants/{plant_id}/modules/parameter/id/value/{module_id}";
+
+#[derive(Debug, Deserialize)]
+struct AuthorizeQuery {
+    redirect_uri: String,
+    state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionRequest {
+    #[serde(rename = "EndPointUrl")]
+    endpoint_url: String,
+}
+
+fn api_error(err: anyhow::Error) -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({ "error": err.to_string() }))
+}
+
+#[get("/authorize")]
+async fn authorize(query: web::Query<AuthorizeQuery>) -> HttpResponse {
+    let state = query.state.as_deref().unwrap_or_default();
+    let separator = if query.redirect_uri.contains('?') { '&' } else { '?' };
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, format!("{}{}code=mock-code&state={}", query.redirect_uri, separator, state)))
+        .finish()
+}
+
+// The bridge refreshes through the real Legrand endpoint, long-lived tokens keep it from trying
+const MOCK_TOKEN_LIFETIME_SECONDS: i64 = 365 * 24 * 3600;
+
+#[post("/token")]
+async fn token() -> HttpResponse {
+    let issued_at = Utc::now().timestamp();
+    HttpResponse::Ok().json(serde_json::json!({
+        "access_token": format!("mock-access-{}", issued_at),
+        "refresh_token": format!("mock-refresh-{}", issued_at),
+        "token_type": "Bearer",
+        "expires_in": MOCK_TOKEN_LIFETIME_SECONDS
+    }))
+}
+
+#[get("/plants")]
+async fn plants(backend: Data<FakeBackend>) -> HttpResponse {
+    let plants: Vec<serde_json::Value> = backend.plants().iter()
+        .map(|plant| serde_json::json!({ "id": plant.id, "name": plant.name }))
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({ "plants": plants }))
+}
+
+#[get("/plants/{plant_id}/topology")]
+async fn topology(backend: Data<FakeBackend>, path: web::Path<String>) -> HttpResponse {
+    match backend.plant(&path.into_inner()) {
+        Ok(plant) => HttpResponse::Ok().json(serde_json::json!({ "plant": plant })),
+        Err(err) => api_error(err)
+    }
+}
+
+async fn device_status(backend: Data<FakeBackend>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (plant_id, module_id) = path.into_inner();
+    match backend.status(&plant_id, &module_id) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(err) => api_error(err)
+    }
+}
+
+async fn set_device_status(backend: Data<FakeBackend>, path: web::Path<(String, String)>, request: web::Json<SetStatusRequest>) -> HttpResponse {
+    let (plant_id, module_id) = path.into_inner();
+    info!("Received command for plant {} module {}: {:?}", plant_id, module_id, request);
+    match backend.record_command(&plant_id, &module_id, request.into_inner()) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => api_error(err)
+    }
+}
+
+#[get("/subscription")]
+async fn subscriptions(backend: Data<FakeBackend>) -> HttpResponse {
+    HttpResponse::Ok().json(backend.subscriptions())
+}
+
+#[post("/plants/{plant_id}/subscription")]
+async fn subscribe(backend: Data<FakeBackend>, path: web::Path<String>, request: web::Json<SubscriptionRequest>) -> HttpResponse {
+    match backend.add_subscription(&path.into_inner(), request.into_inner().endpoint_url) {
+        Ok(subscription) => HttpResponse::Created().json(subscription),
+        Err(err) => api_error(err)
+    }
+}
+
+#[delete("/plants/{plant_id}/subscription/{subscription_id}")]
+async fn unsubscribe(backend: Data<FakeBackend>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (plant_id, subscription_id) = path.into_inner();
+    match backend.remove_subscription(&plant_id, &subscription_id) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => api_error(err)
+    }
+}
+
+/// Commands received since the last call.
+#[get("/mock/commands")]
+async fn commands(backend: Data<FakeBackend>) -> HttpResponse {
+    HttpResponse::Ok().json(backend.take_commands())
+}
+
+/// Sends the current module status to every webhook registered for its plant.
+#[post("/mock/events/{plant_id}/{module_id}")]
+async fn send_event(backend: Data<FakeBackend>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (plant_id, module_id) = path.into_inner();
+    let status = match backend.status(&plant_id, &module_id) {
+        Ok(status) => status,
+        Err(err) => return api_error(err)
+    };
+
+    let events = serde_json::json!([{
+        "data": status,
+        "eventTime": Utc::now().to_rfc3339(),
+        "eventType": "cloud2cloud",
+        "id": format!("mock-event-{}", Utc::now().timestamp_millis()),
+        "subject": "smarther-mock-cloud"
+    }]);
+
+    let client = reqwest::Client::new();
+    let mut delivered = 0;
+    for subscription in backend.subscriptions().iter().filter(|subscription| subscription.plant_id.as_deref() == Some(plant_id.as_str())) {
+        match client.post(&subscription.endpoint_url).json(&events).send().await {
+            Ok(_) => delivered += 1,
+            Err(err) => error!("Failed to deliver event to {}: {}", subscription.endpoint_url, err)
+        }
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "delivered": delivered }))
+}
+
+fn load_backend(fixture_file: &str) -> anyhow::Result<FakeBackend> {
+    let fixture = std::fs::read_to_string(fixture_file)?;
+    let fixture: CachedTopology = serde_json::from_str(&fixture)?;
+
+    let mut backend = FakeBackend::default();
+    for plant in fixture.plants {
+        for module in &plant.modules {
+            let status = ThermostatSnapshot::new(&plant.id, &module.id, Utc::now()).to_module_status()?;
+            backend = backend.with_status(&plant.id, &module.id, status);
+        }
+        backend = backend.with_plant(plant);
+    }
+    Ok(backend)
+}
+
+/// Serves a local stand-in of the Smarther cloud built from a topology fixture.
+pub(crate) async fn mock_cloud(args: &MockCloudArgs, topology_file: &str) -> anyhow::Result<()> {
+    let fixture_file = args.fixture.as_deref().unwrap_or(topology_file);
+    let backend = Data::new(load_backend(fixture_file)?);
+    info!("Serving mock Smarther cloud for {} plants on {}:{}", backend.plants().len(), args.host, args.port);
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(backend.clone())
+            .wrap(Logger::default())
+            .service(authorize)
+            .service(token)
+            .service(plants)
+            .service(topology)
+            .route(MODULE_PATH, web::get().to(device_status))
+            .route(MODULE_PATH, web::post().to(set_device_status))
+            .service(subscriptions)
+            .service(subscribe)
+            .service(unsubscribe)
+            .service(commands)
+            .service(send_event)
+    })
+    .bind((args.host.as_str(), args.port))?
+    .run()
+    .await?;
+
+    Ok(())
+}
@@ -1,78 +1,403 @@
-use std::time::Duration;
+use std::{collections::{BTreeMap, hash_map::DefaultHasher}, hash::{Hash, Hasher}, time::Duration};
 
+use async_channel::{Sender, Receiver, TrySendError};
 use bytes::Bytes;
-use log::{info, error, warn};
-use rumqttc::{MqttOptions, Event::Incoming, Publish, Packet, QoS};
-use smarther::{model::{SetStatusRequest, TimedMeasurement, Measurement, ThermostatFunction, ThermostatMode, ThermostatStatus, LoadState}, SmartherApi};
+use tracing::{info, error, warn, info_span, field, Instrument, Span};
+use rumqttc::{MqttOptions, Event::{Incoming, Outgoing}, Publish, Packet, QoS, LastWill, ConnAck, SubscribeFilter};
+use smarther::{model::{SetStatusRequest, TimedMeasurement, Measurement, ThermostatFunction, ThermostatMode, ThermostatStatus, LoadState}};
 use tokio_util::sync::CancellationToken;
 use anyhow::anyhow;
 
-use crate::Context;
+use crate::{Context, BridgeConfiguration, token_watchdog::AuthState, config::section_changed, logging::request_id, topics::{Topics, TopicTemplates, TopicModule, CommandRoute}};
+
+/// Settings that need a new connection when they change.
+#[derive(PartialEq)]
+struct ConnectionSettings {
+    broker: String,
+    port: u16,
+    username: String,
+    password: String,
+    base_topic: String,
+    clean_session: bool,
+    client_id: Option<String>,
+    keep_alive_seconds: u64,
+    inflight: u16,
+    channel_capacity: usize,
+    topics: TopicTemplates,
+    aliases: BTreeMap<String, String>,
+}
+
+fn connection_settings(configuration: &BridgeConfiguration) -> ConnectionSettings {
+    ConnectionSettings {
+        broker: configuration.mqtt_broker.clone(),
+        port: configuration.mqtt_port,
+        username: configuration.mqtt_username.clone(),
+        password: configuration.mqtt_password.clone(),
+        base_topic: configuration.mqtt_base_topic.clone(),
+        clean_session: configuration.mqtt_clean_session,
+        client_id: configuration.mqtt_client_id.clone(),
+        keep_alive_seconds: configuration.mqtt_keep_alive_seconds,
+        inflight: configuration.mqtt_inflight,
+        channel_capacity: configuration.mqtt_channel_capacity,
+        topics: configuration.mqtt_topics.clone(),
+        aliases: configuration.aliases.clone()
+    }
+}
+
+// FNV-1a, stable across builds unlike the std hasher
+fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn host_name() -> Option<String> {
+    std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+}
+
+/// Configured client id, or one derived from the account plants (the host when there are none),
+/// so that bridges for different accounts do not kick each other off the broker.
+fn client_id(context: &Context) -> String {
+    if let Some(client_id) = context.configuration().mqtt_client_id {
+        return client_id;
+    }
+
+    let mut plant_ids: Vec<&str> = context.topology_cache.plants.iter().map(|plant| plant.id.as_str()).collect();
+    plant_ids.sort_unstable();
+    let seed = match (plant_ids.is_empty(), host_name()) {
+        (false, _) => plant_ids.join(","),
+        (true, Some(host)) => host,
+        (true, None) => return "smarther-mqtt-bridge".to_string()
+    };
+    // Short enough for brokers enforcing the 23 characters limit of MQTT 3.1
+    format!("smarther-bridge-{:06x}", stable_hash(&seed) & 0xffffff)
+}
+
+// Commands waiting for each worker, further commands are dropped so the event loop never waits
+const COMMAND_QUEUE_CAPACITY: usize = 64;
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Exponential reconnection delay with equal jitter, so that bridges restarted together spread out.
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self { attempt: 0 }
+    }
+
+    fn next_delay(&mut self, max_delay: Duration) -> Duration {
+        let delay = RECONNECT_BASE_DELAY.saturating_mul(2u32.saturating_pow(self.attempt)).min(max_delay);
+        self.attempt = self.attempt.saturating_add(1);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+struct MqttCommand {
+    request_id: String,
+    topic: String,
+    route: CommandRoute,
+    payload: Bytes,
+    span: Span,
+}
+
+/// Published on the result topic of the module once a command has been handled.
+#[derive(Debug, Serialize)]
+struct CommandResult {
+    request_id: String,
+    success: bool,
+    error: Option<String>,
+}
 
 pub(crate) async fn mqtt_handler(context: &Context, cancellation_token: CancellationToken) {
-    let configuration = &context.configuration;
-    let mut options = MqttOptions::new("smarther-mqtt-bridge", configuration.mqtt_broker.clone(), configuration.mqtt_port);
-    options.set_credentials(configuration.mqtt_username.clone(), configuration.mqtt_password.clone());
-    let (mqtt_client, mut mqtt_loop)  = rumqttc::AsyncClient::new(options, 100);
+    let workers = context.configuration().mqtt_command_workers.max(1);
+    let (command_senders, command_receivers): (Vec<_>, Vec<_>) = (0..workers)
+        .map(|_| async_channel::bounded(COMMAND_QUEUE_CAPACITY))
+        .unzip();
+    let (result_sender, result_receiver) = async_channel::unbounded();
 
-    // Handle subscriptions for the current plant topology
-    for plant in &context.topology_cache.plants {
-        for module in &plant.modules {
-            let device_topic = format!("{}/{}/{}/set_status", &configuration.mqtt_base_topic, &plant.id, &module.id);
-            mqtt_client.subscribe(device_topic, rumqttc::QoS::AtLeastOnce).await.unwrap();
+    let connection = async {
+        mqtt_connection(context, command_senders, result_receiver, cancellation_token.clone()).await;
+        // Webhooks are unregistered only once the queued updates and the offline availability went out
+        context.mqtt_offline.cancel();
+    };
+    tokio::join!(
+        connection,
+        command_workers(context, command_receivers, result_sender, cancellation_token.clone())
+    );
+}
+
+/// Runs the commands of each queue in order, the queues themselves run concurrently.
+async fn command_workers(context: &Context, command_receivers: Vec<Receiver<MqttCommand>>, results: Sender<(String, CommandResult)>, cancellation_token: CancellationToken) {
+    let workers = futures::future::join_all(command_receivers.into_iter().map(|commands| command_worker(context, commands, results.clone())));
+    let shutdown_deadline = async {
+        cancellation_token.cancelled().await;
+        tokio::time::sleep_until(context.shutdown_deadline()).await;
+    };
+
+    tokio::select! {
+        _ = workers => {},
+        _ = shutdown_deadline => {
+            warn!("Dropping MQTT commands still in progress after the shutdown timeout");
         }
     }
+}
 
-    tokio::select! {
-        _ = cancellation_token.cancelled() => {},
-        _ = mqtt_command_handler(context, &mut mqtt_loop) => {},
-        _ = mqtt_status_change_handler(context, mqtt_client) => {}
+async fn command_worker(context: &Context, commands: Receiver<MqttCommand>, results: Sender<(String, CommandResult)>) {
+    while let Ok(command) = commands.recv().await {
+        let span = command.span;
+        let result = try_update_plant_status(context, &command.topic, command.route.module.as_ref(), &command.payload).instrument(span.clone()).await;
+        match &result {
+            Ok(_) => { span.record("outcome", "ok"); },
+            Err(err) => {
+                span.record("outcome", "error");
+                error!(parent: &span, "Error while updating plant status: {}", err);
+            }
+        }
+
+        let command_result = CommandResult {
+            request_id: command.request_id,
+            success: result.is_ok(),
+            error: result.err().map(|err| err.to_string())
+        };
+        if results.send((command.route.result_topic, command_result)).await.is_err() {
+            error!(parent: &span, "Failed to send command result to MQTT handler");
+        }
+    }
+}
+
+/// Routes a command once and queues it on the worker owning its module, so that commands for a module
+/// keep their order whether they arrive on the alias or on the id topic.
+fn dispatch_command(context: &Context, topics: &Topics, command_senders: &[Sender<MqttCommand>], topic: &str, payload: &Bytes) {
+    let Some(route) = topics.route_command(&context.topology_cache, topic) else {
+        return;
+    };
+    let request_id = request_id();
+    let span = info_span!("mqtt_command", request_id = %request_id, topic = %topic, plant_id = field::Empty, module_id = field::Empty, outcome = field::Empty);
+    let mut hasher = DefaultHasher::new();
+    match &route.module {
+        Some(module) => (&module.plant_id, &module.module_id).hash(&mut hasher),
+        // Unknown modules only get an error, their order does not matter
+        None => topic.hash(&mut hasher)
+    }
+    let worker = (hasher.finish() % command_senders.len() as u64) as usize;
+
+    let command = MqttCommand { request_id, topic: topic.to_string(), route, payload: payload.clone(), span: span.clone() };
+    match command_senders[worker].try_send(command) {
+        Ok(_) => {},
+        Err(TrySendError::Full(_)) => {
+            span.record("outcome", "dropped");
+            warn!(parent: &span, "Command queue full, dropping command on {}", topic);
+        },
+        Err(TrySendError::Closed(_)) => {
+            span.record("outcome", "dropped");
+            error!(parent: &span, "Command workers stopped, dropping command on {}", topic);
+        }
+    }
+}
+
+async fn mqtt_connection(context: &Context, command_senders: Vec<Sender<MqttCommand>>, results: Receiver<(String, CommandResult)>, cancellation_token: CancellationToken) {
+    let mut configuration_updates = context.configuration.subscribe();
+    loop {
+        let configuration = configuration_updates.borrow().clone();
+        let topics = match Topics::new(&configuration) {
+            Ok(topics) => topics,
+            Err(err) => {
+                error!("Not connecting to MQTT until the topic templates are fixed: {}", err);
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => { break; },
+                    _ = section_changed(&mut configuration_updates, connection_settings) => { continue; }
+                }
+            }
+        };
+        let client_id = client_id(context);
+        info!("Connecting to MQTT broker {}:{} as {}", configuration.mqtt_broker, configuration.mqtt_port, client_id);
+        let mut options = MqttOptions::new(client_id, configuration.mqtt_broker.clone(), configuration.mqtt_port);
+        options.set_credentials(configuration.mqtt_username.clone(), configuration.mqtt_password.clone());
+        options.set_last_will(LastWill::new(topics.availability(), "offline", QoS::AtLeastOnce, true));
+        options.set_clean_session(configuration.mqtt_clean_session);
+        options.set_keep_alive(Duration::from_secs(configuration.mqtt_keep_alive_seconds));
+        options.set_inflight(configuration.mqtt_inflight);
+        let (mqtt_client, mut mqtt_loop)  = rumqttc::AsyncClient::new(options, configuration.mqtt_channel_capacity);
+
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {},
+            _ = mqtt_command_handler(context, &topics, &command_senders, &mqtt_client, &mut mqtt_loop) => {},
+            _ = mqtt_status_change_handler(context, mqtt_client.clone()) => {},
+            _ = mqtt_auth_state_handler(context, mqtt_client.clone()) => {},
+            _ = mqtt_result_handler(&results, mqtt_client.clone()) => {},
+            _ = section_changed(&mut configuration_updates, connection_settings) => {
+                info!("MQTT settings changed, reconnecting");
+                continue;
+            }
+        }
+
+        if tokio::time::timeout_at(context.shutdown_deadline(), drain_and_disconnect(context, &topics, &mqtt_client, &mut mqtt_loop)).await.is_err() {
+            warn!("MQTT shutdown did not complete within {} seconds", configuration.shutdown_timeout_seconds);
+        }
+        break;
     }
 }
 
-async fn try_update_plant_status(context: &Context, topic: &str, payload: &Bytes) -> anyhow::Result<()> {
-    let topic_parts: Vec<&str> = topic.split('/').collect();
-    if topic_parts.len() == 4 && topic_parts[3] == "set_status" {
-        let plant_id = topic_parts[1];
-        let module_id = topic_parts[2];
-        let payload = String::from_utf8(payload.to_vec())?;
-        let status_change_request: SetStatusRequest = serde_json::from_str(&payload)?;
+/// Publishes the status updates still queued, then the offline availability, then disconnects.
+async fn drain_and_disconnect(context: &Context, topics: &Topics, mqtt_client: &rumqttc::AsyncClient, mqtt_loop: &mut rumqttc::EventLoop) {
+    info!("Publishing {} pending status updates before disconnecting", context.status_updates.1.len());
+    let publisher = async {
+        // Returns once the webhook server closed the queue and it is empty
+        mqtt_status_change_handler(context, mqtt_client.clone()).await;
+        if let Err(err) = mqtt_client.publish(topics.availability(), QoS::AtLeastOnce, true, "offline").await {
+            error!("Error while publishing availability: {}", err);
+        }
+        if let Err(err) = mqtt_client.disconnect().await {
+            error!("Error while disconnecting from MQTT: {}", err);
+        }
+    };
+    let poller = async {
+        loop {
+            match mqtt_loop.poll().await {
+                Ok(Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+                Ok(_) => {},
+                Err(err) => {
+                    warn!("MQTT Reported Error while disconnecting: {}", err);
+                    break;
+                }
+            }
+        }
+    };
+    tokio::join!(publisher, poller);
+}
 
-        context.refresh_token_if_needed().await?;
+/// Publishes the queued status updates until the queue is closed, without a last will, availability,
+/// auth state or command subscription, under its own client id so that a running bridge stays connected.
+pub(crate) async fn mqtt_status_publisher(context: &Context, cancellation_token: CancellationToken) {
+    let configuration = context.configuration();
+    let client_id = format!("smarther-replay-{:06x}", rand::random::<u32>() & 0xffffff);
+    info!("Connecting to MQTT broker {}:{} as {}", configuration.mqtt_broker, configuration.mqtt_port, client_id);
+    let mut options = MqttOptions::new(client_id, configuration.mqtt_broker.clone(), configuration.mqtt_port);
+    options.set_credentials(configuration.mqtt_username.clone(), configuration.mqtt_password.clone());
+    options.set_keep_alive(Duration::from_secs(configuration.mqtt_keep_alive_seconds));
+    options.set_inflight(configuration.mqtt_inflight);
+    let (mqtt_client, mut mqtt_loop) = rumqttc::AsyncClient::new(options, configuration.mqtt_channel_capacity);
 
-        let client = SmartherApi::default();
-        let auth_info = context.auth_info.borrow().clone();
-        let client = client.with_authorization(auth_info)?;
+    let publisher = async {
+        mqtt_status_change_handler(context, mqtt_client.clone()).await;
+        if let Err(err) = mqtt_client.disconnect().await {
+            error!("Error while disconnecting from MQTT: {}", err);
+        }
+    };
+    let poller = async {
+        loop {
+            match mqtt_loop.poll().await {
+                Ok(Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+                Ok(_) => {},
+                Err(err) => {
+                    warn!("MQTT Reported Error: {}", err);
+                    tokio::time::sleep(RECONNECT_BASE_DELAY).await;
+                }
+            }
+        }
+    };
+    let shutdown_deadline = async {
+        cancellation_token.cancelled().await;
+        tokio::time::sleep(configuration.shutdown_timeout()).await;
+    };
 
-        info!("Setting status for plant {} module {} to {:?}", plant_id, module_id, status_change_request);
-        client.set_device_status(plant_id, module_id, status_change_request).await?;
+    tokio::select! {
+        _ = futures::future::join(publisher, poller) => {},
+        _ = shutdown_deadline => {
+            warn!("Dropping status updates not published within the shutdown timeout");
+        }
     }
+}
+
+async fn try_update_plant_status(context: &Context, topic: &str, module: Option<&TopicModule>, payload: &Bytes) -> anyhow::Result<()> {
+    let Some(TopicModule { plant_id, module_id, .. }) = module else {
+        return Err(anyhow!("No module matches command topic {}", topic));
+    };
+    Span::current().record("plant_id", plant_id.as_str()).record("module_id", module_id.as_str());
+
+    let payload = String::from_utf8(payload.to_vec())?;
+    let status_change_request: SetStatusRequest = serde_json::from_str(&payload)?;
+
+    context.refresh_token_if_needed().await?;
+
+    let auth_info = context.auth_info.borrow().clone();
+    info!("Setting status for plant {} module {} to {:?}", plant_id, module_id, status_change_request);
+    context.backend.set_device_status(&auth_info, plant_id, module_id, status_change_request).await?;
     Ok(())
 }
 
-async fn mqtt_command_handler(context: &Context, mqtt_loop: &mut rumqttc::EventLoop) {
+async fn mqtt_result_handler(results: &Receiver<(String, CommandResult)>, mqtt_client: rumqttc::AsyncClient) {
+    while let Ok((topic, result)) = results.recv().await {
+        match serde_json::to_string(&result) {
+            Ok(payload) => if let Err(err) = mqtt_client.publish(topic, QoS::AtLeastOnce, false, payload).await {
+                error!("Error while publishing command result: {}", err);
+            },
+            Err(err) => error!("Error while serializing command result: {}", err)
+        }
+    }
+}
+
+fn command_subscriptions(topics: &Topics) -> Vec<SubscribeFilter> {
+    // Unknown plants and modules are answered on their result topic instead of being ignored
+    vec!(SubscribeFilter::new(topics.command_filter(), QoS::AtLeastOnce))
+}
+
+async fn mqtt_command_handler(context: &Context, topics: &Topics, command_senders: &[Sender<MqttCommand>], mqtt_client: &rumqttc::AsyncClient, mqtt_loop: &mut rumqttc::EventLoop) {
+    let mut backoff = Backoff::new();
+    // A new client has no subscriptions yet, even when the broker kept a session for its id
+    let mut subscribed = false;
     loop {
         let mut mqtt_event = mqtt_loop.poll().await;
         while let Ok(event) = &mqtt_event {
-            if let Incoming(Packet::Publish(Publish { topic, payload, .. })) = event {
-               if let Err(err) = try_update_plant_status(context, topic, payload).await {
-                   error!("Error while updating plant status: {}", err);
-               }
+            context.health.record_mqtt_poll(None);
+            match event {
+                Incoming(Packet::Publish(Publish { topic, payload, .. })) => {
+                    dispatch_command(context, topics, command_senders, topic, payload);
+                },
+                Incoming(Packet::ConnAck(ConnAck { session_present, .. })) => {
+                    context.health.record_mqtt_poll(Some(true));
+                    backoff.reset();
+                    if !*session_present || !subscribed {
+                        // Not awaited either, the subscriptions are sent by this loop
+                        match mqtt_client.try_subscribe_many(command_subscriptions(topics)) {
+                            Ok(_) => subscribed = true,
+                            Err(err) => error!("Error while subscribing to command topics: {}", err)
+                        }
+                    }
+                    // Not awaited, the request channel is only emptied by this loop
+                    if let Err(err) = mqtt_client.try_publish(topics.availability(), QoS::AtLeastOnce, true, "online") {
+                        error!("Error while publishing availability: {}", err);
+                    }
+                },
+                _ => {}
             }
 
             mqtt_event = mqtt_loop.poll().await;
         }
         // Reconnect timeout
-        warn!("MQTT connection lost, reconnecting in 5 seconds...");
+        context.health.record_mqtt_poll(Some(false));
+        let delay = backoff.next_delay(Duration::from_secs(context.configuration().mqtt_reconnect_max_delay_seconds));
+        warn!("MQTT connection lost, reconnecting in {} ms...", delay.as_millis());
         if let Err(err) = &mqtt_event {
             warn!("MQTT Reported Error: {}", err);
         }
-        tokio::time::sleep(Duration::from_millis(5000)).await;
+        tokio::time::sleep(delay).await;
     }
 }
 
 #[derive(Debug, Serialize)]
-struct MeasurementSummary {
+pub(crate) struct MeasurementSummary {
+    plant_id: String,
+    plant_name: String,
+    module_id: String,
+    module_name: String,
     temperature: Option<TimedMeasurement>,
     humidity: Option<TimedMeasurement>,
     set_point: Option<Measurement>,
@@ -83,26 +408,38 @@ struct MeasurementSummary {
     activation_time: Option<String>
 }
 
-async fn try_parse_and_publish_status(context: &Context, status: &ThermostatStatus, mqtt_client: &rumqttc::AsyncClient) -> anyhow::Result<()> {
+impl MeasurementSummary {
+    pub fn new(status: &ThermostatStatus, module: &TopicModule) -> Self {
+        let last_temperature = status.thermometer.as_ref().and_then(|inst| inst.last_measurement());
+        let last_pressure = status.hygrometer.as_ref().and_then(|inst| inst.last_measurement());
+        MeasurementSummary {
+            plant_id: module.plant_id.clone(),
+            plant_name: module.plant_name.clone(),
+            module_id: module.module_id.clone(),
+            module_name: module.module_name.clone(),
+            temperature: last_temperature.cloned(),
+            humidity: last_pressure.cloned(),
+            set_point: status.set_point.clone(),
+            load_state: status.load_state.clone(),
+            mode: status.mode.clone(),
+            function: status.function.clone(),
+            time: status.time.to_rfc3339(),
+            activation_time: status.activation_time.map(|t| t.to_rfc3339())
+        }
+    }
+}
+
+pub(crate) fn status_message(context: &Context, status: &ThermostatStatus) -> anyhow::Result<(String, MeasurementSummary)> {
     let sender_details = status.sender.as_ref().ok_or(anyhow!("No sender details found"))?;
     let plant_details = sender_details.plant.as_ref().ok_or(anyhow!("No plant details found"))?;
 
+    let topics = Topics::new(&context.configuration())?;
+    let module = topics.module(&context.topology_cache, &plant_details.id, &plant_details.module.id);
+    Ok((topics.status(&module), MeasurementSummary::new(status, &module)))
+}
 
-    let device_status_topic = format!("{}/{}/{}/status", &context.configuration.mqtt_base_topic, plant_details.id, plant_details.module.id);
-    
-    let last_temperature = status.thermometer.as_ref().and_then(|inst| inst.last_measurement());
-    let last_pressure = status.hygrometer.as_ref().and_then(|inst| inst.last_measurement());
-    let status_summary = MeasurementSummary {
-        temperature: last_temperature.cloned(),
-        humidity: last_pressure.cloned(),
-        set_point: status.set_point.clone(),
-        load_state: status.load_state.clone(),
-        mode: status.mode.clone(),
-        function: status.function.clone(),
-        time: status.time.to_rfc3339(),
-        activation_time: status.activation_time.map(|t| t.to_rfc3339())
-    };
-
+async fn try_parse_and_publish_status(context: &Context, status: &ThermostatStatus, mqtt_client: &rumqttc::AsyncClient) -> anyhow::Result<()> {
+    let (device_status_topic, status_summary) = status_message(context, status)?;
     mqtt_client.publish(device_status_topic, QoS::AtLeastOnce, false, serde_json::to_string(&status_summary)?).await?;
     Ok(())
 }
@@ -115,4 +452,188 @@ async fn mqtt_status_change_handler(context: &Context, mqtt_client: rumqttc::Asy
             }
         }
     }
-}
\ No newline at end of file
+}
+
+async fn try_publish_auth_state(context: &Context, auth_state: &AuthState, mqtt_client: &rumqttc::AsyncClient) -> anyhow::Result<()> {
+    let auth_topic = Topics::new(&context.configuration())?.auth();
+    mqtt_client.publish(auth_topic, QoS::AtLeastOnce, true, serde_json::to_string(auth_state)?).await?;
+    Ok(())
+}
+
+async fn mqtt_auth_state_handler(context: &Context, mqtt_client: rumqttc::AsyncClient) {
+    let initial_state = context.auth_state.borrow().clone();
+    if let Err(err) = try_publish_auth_state(context, &initial_state, &mqtt_client).await {
+        error!("Error while publishing auth state: {}", err);
+    }
+
+    while let Ok(auth_state) = context.auth_updates.1.recv().await {
+        if let Err(err) = try_publish_auth_state(context, &auth_state, &mqtt_client).await {
+            error!("Error while publishing auth state: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{Context, BridgeConfiguration, backend::fixtures::{self, PLANT_ID, MODULE_ID}, topics::{Topics, CommandRoute}};
+
+    use super::{try_update_plant_status, dispatch_command, status_message, client_id, Backoff, RECONNECT_BASE_DELAY};
+
+    fn route(context: &Context, topic: &str) -> CommandRoute {
+        Topics::new(&context.configuration()).unwrap().route_command(&context.topology_cache, topic).unwrap()
+    }
+
+    fn set_status_payload() -> Bytes {
+        Bytes::from(serde_json::json!({
+            "function": "HEATING",
+            "mode": "MANUAL",
+            "setPoint": { "value": "21.5", "unit": "C" },
+            "programs": [{ "number": 0 }]
+        }).to_string())
+    }
+
+    #[tokio::test]
+    async fn set_status_reaches_backend() {
+        let backend = fixtures::backend();
+        let context = fixtures::context(backend.clone());
+
+        let topic = format!("smarther/{}/{}/set_status", PLANT_ID, MODULE_ID);
+        try_update_plant_status(&context, &topic, route(&context, &topic).module.as_ref(), &set_status_payload()).await.unwrap();
+
+        let commands = backend.take_commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].plant_id, PLANT_ID);
+        assert_eq!(commands[0].module_id, MODULE_ID);
+    }
+
+    #[tokio::test]
+    async fn consecutive_commands_do_not_wait_for_the_token_refresher() {
+        let backend = fixtures::backend();
+        let context = fixtures::context(backend.clone());
+
+        let topic = format!("smarther/{}/{}/set_status", PLANT_ID, MODULE_ID);
+        for _ in 0..3 {
+            tokio::time::timeout(std::time::Duration::from_secs(1), try_update_plant_status(&context, &topic, route(&context, &topic).module.as_ref(), &set_status_payload())).await
+                .expect("Command blocked").unwrap();
+        }
+        assert_eq!(backend.take_commands().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn set_status_for_unknown_module_fails() {
+        let backend = fixtures::backend();
+        let context = fixtures::context(backend.clone());
+
+        let topic = format!("smarther/{}/unknown/set_status", PLANT_ID);
+        assert!(try_update_plant_status(&context, &topic, route(&context, &topic).module.as_ref(), &set_status_payload()).await.is_err());
+        assert!(backend.take_commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn set_status_under_nested_base_topic_reaches_backend() {
+        let backend = fixtures::backend();
+        let configuration = BridgeConfiguration { mqtt_base_topic: "home/heating".to_string(), ..BridgeConfiguration::default() };
+        let context = fixtures::context_with_configuration(backend.clone(), configuration);
+
+        let topic = format!("home/heating/{}/{}/set_status", PLANT_ID, MODULE_ID);
+        try_update_plant_status(&context, &topic, route(&context, &topic).module.as_ref(), &set_status_payload()).await.unwrap();
+        assert_eq!(backend.take_commands().len(), 1);
+    }
+
+    #[test]
+    fn router_requires_base_topic_prefix() {
+        let configuration = BridgeConfiguration { mqtt_base_topic: "home/heating".to_string(), ..BridgeConfiguration::default() };
+        let context = fixtures::context_with_configuration(fixtures::backend(), configuration);
+
+        let known = route(&context, &format!("home/heating/{}/{}/set_status", PLANT_ID, MODULE_ID));
+        assert_eq!(known.result_topic, "home/heating/home/living-room/result");
+        assert!(known.module.is_some());
+        let unknown = route(&context, "home/heating/plant/module/set_status");
+        assert_eq!(unknown.result_topic, "home/heating/plant/module/result");
+        assert!(unknown.module.is_none());
+        let topics = Topics::new(&context.configuration()).unwrap();
+        assert!(topics.route_command(&context.topology_cache, "home/heating2/plant/module/set_status").is_none());
+        assert!(topics.route_command(&context.topology_cache, "home/heating/plant/module/status").is_none());
+    }
+
+    #[test]
+    fn derived_client_id_is_stable_and_short() {
+        let first = client_id(&fixtures::context(fixtures::backend()));
+        let second = client_id(&fixtures::context(fixtures::backend()));
+        assert_eq!(first, second);
+        assert!(first.starts_with("smarther-bridge-"));
+        assert!(first.len() <= 23);
+
+        let configuration = BridgeConfiguration { mqtt_client_id: Some("living-room-bridge".to_string()), ..BridgeConfiguration::default() };
+        assert_eq!(client_id(&fixtures::context_with_configuration(fixtures::backend(), configuration)), "living-room-bridge");
+    }
+
+    #[test]
+    fn backoff_stays_within_the_cap() {
+        let max_delay = std::time::Duration::from_secs(10);
+        let mut backoff = Backoff::new();
+        let first = backoff.next_delay(max_delay);
+        assert!(first >= RECONNECT_BASE_DELAY / 2 && first <= RECONNECT_BASE_DELAY);
+        for _ in 0..64 {
+            assert!(backoff.next_delay(max_delay) <= max_delay);
+        }
+        assert!(backoff.next_delay(max_delay) >= max_delay / 2);
+
+        backoff.reset();
+        assert!(backoff.next_delay(max_delay) <= RECONNECT_BASE_DELAY);
+    }
+
+    #[test]
+    fn alias_and_id_commands_share_a_worker() {
+        let context = fixtures::context(fixtures::backend());
+        let topics = Topics::new(&context.configuration()).unwrap();
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..4).map(|_| async_channel::bounded(2)).unzip();
+
+        dispatch_command(&context, &topics, &senders, "smarther/home/living-room/set_status", &set_status_payload());
+        dispatch_command(&context, &topics, &senders, &format!("smarther/{}/{}/set_status", PLANT_ID, MODULE_ID), &set_status_payload());
+
+        let receiver = receivers.iter().find(|receiver| !receiver.is_empty()).unwrap();
+        assert_eq!(receiver.len(), 2);
+        let command = receiver.try_recv().unwrap();
+        assert_eq!(command.route.module.unwrap().module_id, MODULE_ID);
+        assert_eq!(command.route.result_topic, "smarther/home/living-room/result");
+    }
+
+    #[test]
+    fn other_topics_are_ignored() {
+        let context = fixtures::context(fixtures::backend());
+        let topics = Topics::new(&context.configuration()).unwrap();
+        let (sender, receiver) = async_channel::bounded(1);
+
+        let topic = format!("smarther/{}/{}/status", PLANT_ID, MODULE_ID);
+        dispatch_command(&context, &topics, &[sender], &topic, &Bytes::from_static(b"{}"));
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_payload_is_rejected() {
+        let backend = fixtures::backend();
+        let context = fixtures::context(backend.clone());
+
+        let topic = format!("smarther/{}/{}/set_status", PLANT_ID, MODULE_ID);
+        assert!(try_update_plant_status(&context, &topic, route(&context, &topic).module.as_ref(), &Bytes::from_static(b"not json")).await.is_err());
+        assert!(backend.take_commands().is_empty());
+    }
+
+    #[test]
+    fn status_message_uses_sender_topic() {
+        let context = fixtures::context(fixtures::backend());
+        let status = fixtures::module_status(PLANT_ID, MODULE_ID, 19.5);
+
+        let (topic, summary) = status_message(&context, &status.chronothermostats[0]).unwrap();
+        let summary = serde_json::to_value(summary).unwrap();
+        assert_eq!(topic, "smarther/home/living-room/status");
+        assert_eq!(summary["module_id"], MODULE_ID);
+        assert_eq!(summary["module_name"], "Living Room");
+        assert!(!summary["temperature"].is_null());
+        assert!(!summary["humidity"].is_null());
+        assert!(!summary["set_point"].is_null());
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::anyhow;
+use chrono::{Utc, Duration};
+use rand::{Rng, distributions::Alphanumeric};
+use tracing::info;
+use reqwest::Url;
+use smarther::AuthorizationInfo;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+pub(crate) const DEFAULT_AUTH_URI: &str = "https://partners-login.eliotbylegrand.com";
+const OAUTH_STATE_LENGTH: usize = 32;
+
+/// Random value tying an authorization callback to the flow that started it.
+pub(crate) fn new_state() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(OAUTH_STATE_LENGTH).map(char::from).collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: i64,
+}
+
+pub(crate) struct OAuthClient<'a> {
+    pub client_id: &'a str,
+    pub client_secret: &'a str,
+    pub subscription_key: &'a str,
+    pub base_uri: Option<&'a str>,
+    pub auth_uri: &'a str,
+    pub redirect_uri: &'a str,
+}
+
+impl<'a> OAuthClient<'a> {
+    pub fn authorization_url(&self, state: &str) -> anyhow::Result<Url> {
+        let url = Url::parse_with_params(&format!("{}/authorize", self.auth_uri), &[
+            ("client_id", self.client_id),
+            ("response_type", "code"),
+            ("redirect_uri", self.redirect_uri),
+            ("state", state),
+        ])?;
+        Ok(url)
+    }
+
+    pub async fn exchange_code(&self, code: &str) -> anyhow::Result<AuthorizationInfo> {
+        let response: TokenResponse = reqwest::Client::new()
+            .post(format!("{}/token", self.auth_uri))
+            .form(&[
+                ("client_id", self.client_id),
+                ("client_secret", self.client_secret),
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.redirect_uri),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        self.authorization_info(&response.access_token, &response.refresh_token, Utc::now() + Duration::seconds(response.expires_in))
+    }
+
+    /// Builds an already expired authorization around an existing refresh token,
+    /// the first refresh will then fetch a valid access token.
+    pub fn import_refresh_token(&self, refresh_token: &str) -> anyhow::Result<AuthorizationInfo> {
+        self.authorization_info("", refresh_token, Utc::now() - Duration::seconds(1))
+    }
+
+    pub async fn headless_access_code(&self) -> anyhow::Result<AuthorizationInfo> {
+        let state = new_state();
+        println!("Open the following URL in a browser and authorize the bridge:");
+        println!();
+        println!("{}", self.authorization_url(&state)?);
+        println!();
+        println!("Then paste the URL you were redirected to (or just its code parameter):");
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let input = lines.next_line().await?.ok_or(anyhow!("No authorization code provided"))?;
+        let code = parse_authorization_code(input.trim(), &state)?;
+
+        info!("Exchanging authorization code for tokens");
+        self.exchange_code(&code).await
+    }
+
+    fn authorization_info(&self, access_token: &str, refresh_token: &str, expires_on: chrono::DateTime<Utc>) -> anyhow::Result<AuthorizationInfo> {
+        let auth_info = serde_json::from_value(serde_json::json!({
+            "client_id": self.client_id,
+            "client_secret": self.client_secret,
+            "subscription_key": self.subscription_key,
+            "base_uri": self.base_uri,
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "expires_on": expires_on
+        }))?;
+        Ok(auth_info)
+    }
+}
+
+/// Extracts the code from a redirect URL, checking its state, or takes the input as the code itself.
+fn parse_authorization_code(input: &str, expected_state: &str) -> anyhow::Result<String> {
+    if input.is_empty() {
+        return Err(anyhow!("No authorization code provided"));
+    }
+
+    match Url::parse(input) {
+        Ok(url) => {
+            if let Some((_, error)) = url.query_pairs().find(|(key, _)| key == "error") {
+                return Err(anyhow!("Authorization failed: {}", error));
+            }
+            if !url.query_pairs().any(|(key, state)| key == "state" && state == expected_state) {
+                return Err(anyhow!("The redirect URL does not belong to this authorization, please start again"));
+            }
+            url.query_pairs()
+                .find(|(key, _)| key == "code")
+                .map(|(_, code)| code.into_owned())
+                .ok_or(anyhow!("No code parameter found in {}", input))
+        },
+        Err(_) => Ok(input.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{OAuthClient, parse_authorization_code};
+
+    fn client() -> OAuthClient<'static> {
+        OAuthClient {
+            client_id: "client",
+            client_secret: "secret",
+            subscription_key: "key",
+            base_uri: None,
+            auth_uri: "https://login.example.com",
+            redirect_uri: "http://localhost:8080/onboarding/callback"
+        }
+    }
+
+    #[test]
+    fn authorization_url_carries_the_client_redirect_and_state() {
+        let url = client().authorization_url("state-1").unwrap();
+        assert_eq!(url.as_str().split('?').next(), Some("https://login.example.com/authorize"));
+
+        let parameters: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(parameters, [
+            ("client_id".to_string(), "client".to_string()),
+            ("response_type".to_string(), "code".to_string()),
+            ("redirect_uri".to_string(), "http://localhost:8080/onboarding/callback".to_string()),
+            ("state".to_string(), "state-1".to_string())
+        ]);
+    }
+
+    #[test]
+    fn redirect_with_another_state_is_rejected() {
+        let redirect = "http://localhost:8080/onboarding/callback?code=abc&state=state-1";
+        assert_eq!(parse_authorization_code(redirect, "state-1").unwrap(), "abc");
+        assert!(parse_authorization_code(redirect, "state-2").is_err());
+        assert!(parse_authorization_code("http://localhost:8080/onboarding/callback?code=abc", "state-1").is_err());
+        assert_eq!(parse_authorization_code("abc", "state-1").unwrap(), "abc");
+    }
+
+    #[test]
+    fn imported_refresh_token_is_refreshed_first() {
+        let auth_info = client().import_refresh_token("refresh-1").unwrap();
+        assert!(auth_info.expires_on < Utc::now());
+        assert!(auth_info.is_refresh_needed());
+
+        let auth_info = serde_json::to_value(&auth_info).unwrap();
+        assert_eq!(auth_info["refresh_token"], "refresh-1");
+        assert_eq!(auth_info["client_id"], "client");
+    }
+}
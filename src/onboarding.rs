@@ -0,0 +1,207 @@
+use std::sync::Mutex;
+
+use actix_web::{get, post, web::{Data, self}, HttpServer, App, HttpResponse, middleware::Logger, http::header};
+use async_channel::Sender;
+use tracing::{info, error};
+use smarther::AuthorizationInfo;
+
+use crate::{BridgeConfiguration, oauth::{self, OAuthClient, DEFAULT_AUTH_URI}};
+
+const ONBOARDING_CALLBACK_PATH: &str = "/onboarding/callback";
+
+#[derive(Debug, Deserialize, Clone)]
+struct OnboardingCredentials {
+    client_id: String,
+    client_secret: String,
+    subscription_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OnboardingCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Credentials submitted on the page and the OAuth state of the flow they started.
+#[derive(Debug, Clone)]
+struct PendingAuthorization {
+    credentials: OnboardingCredentials,
+    state: String,
+}
+
+struct OnboardingState {
+    redirect_uri: String,
+    pending: Mutex<Option<PendingAuthorization>>,
+    completed: Sender<AuthorizationInfo>,
+}
+
+impl OnboardingCredentials {
+    fn oauth_client<'a>(&'a self, redirect_uri: &'a str) -> OAuthClient<'a> {
+        OAuthClient {
+            client_id: &self.client_id,
+            client_secret: &self.client_secret,
+            subscription_key: &self.subscription_key,
+            base_uri: None,
+            auth_uri: DEFAULT_AUTH_URI,
+            redirect_uri
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars().fold(String::with_capacity(text.len()), |mut escaped, character| {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(character)
+        }
+        escaped
+    })
+}
+
+#[get("/")]
+async fn index(state: Data<OnboardingState>) -> HttpResponse {
+    let page = format!(r#"<!DOCTYPE html>
+<html>
+<head><title>Smarther MQTT Bridge setup</title></head>
+<body>
+<h1>Smarther MQTT Bridge setup</h1>
+<p>The bridge has no tokens yet. Register <code>{redirect_uri}</code> as redirect URI of your Legrand application, then fill in its credentials.</p>
+<form method="post" action="/onboarding">
+<p><label>Client ID <input name="client_id" required></label></p>
+<p><label>Client secret <input name="client_secret" type="password" required></label></p>
+<p><label>Subscription key <input name="subscription_key" required></label></p>
+<p><button type="submit">Authorize</button></p>
+</form>
+</body>
+</html>"#, redirect_uri = escape_html(&state.redirect_uri));
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page)
+}
+
+#[post("/onboarding")]
+async fn start_authorization(state: Data<OnboardingState>, form: web::Form<OnboardingCredentials>) -> HttpResponse {
+    let credentials = form.into_inner();
+    let oauth_state = oauth::new_state();
+    let authorization_url = match credentials.oauth_client(&state.redirect_uri).authorization_url(&oauth_state) {
+        Ok(url) => url,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid credentials: {}", err))
+    };
+
+    state.pending.lock().unwrap().replace(PendingAuthorization { credentials, state: oauth_state });
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, authorization_url.to_string()))
+        .finish()
+}
+
+#[get("/onboarding/callback")]
+async fn authorization_callback(state: Data<OnboardingState>, query: web::Query<OnboardingCallback>) -> HttpResponse {
+    let pending = state.pending.lock().unwrap().clone();
+    let (credentials, code) = match (pending, &query.code, &query.error) {
+        (_, _, Some(error)) => return HttpResponse::BadRequest().body(format!("Authorization failed: {}", error)),
+        // Only the browser that submitted the credentials knows the state, anything else is a forged callback
+        (Some(pending), Some(code), _) if query.state.as_deref() == Some(pending.state.as_str()) => (pending.credentials, code),
+        _ => return HttpResponse::BadRequest().body("Unexpected authorization callback, please start again from /")
+    };
+
+    match credentials.oauth_client(&state.redirect_uri).exchange_code(code).await {
+        Ok(auth_info) => {
+            if state.completed.send(auth_info).await.is_err() {
+                error!("Failed to hand over tokens to the bridge");
+                return HttpResponse::InternalServerError().body("Failed to store tokens");
+            }
+            HttpResponse::Ok().body("Bridge configured, you can close this page.")
+        },
+        Err(err) => {
+            error!("Failed to exchange authorization code: {}", err);
+            HttpResponse::BadGateway().body(format!("Failed to exchange authorization code: {}", err))
+        }
+    }
+}
+
+/// Serves the onboarding page until the OAuth flow completes, then stores and returns the tokens.
+pub(crate) async fn onboarding_handler(configuration: &BridgeConfiguration, auth_file: &str) -> anyhow::Result<AuthorizationInfo> {
+    let public_uri = configuration.webhook_endpoint.clone()
+        .unwrap_or_else(|| format!("http://{}:{}", configuration.listen_host, configuration.listen_port));
+    let (sender, receiver) = async_channel::bounded(1);
+    let state = Data::new(OnboardingState {
+        redirect_uri: format!("{}{}", public_uri.trim_end_matches('/'), ONBOARDING_CALLBACK_PATH),
+        pending: Mutex::new(None),
+        completed: sender
+    });
+
+    info!("No tokens found, serving onboarding page on {}:{}", configuration.listen_host, configuration.listen_port);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .wrap(Logger::default())
+            .service(index)
+            .service(start_authorization)
+            .service(authorization_callback)
+    })
+    .bind((configuration.listen_host.as_str(), configuration.listen_port))?
+    .run();
+
+    let server_handle = server.handle();
+    let auth_info = tokio::select! {
+        result = server => {
+            result?;
+            return Err(anyhow::anyhow!("Onboarding server stopped before completing setup"));
+        },
+        auth_info = receiver.recv() => auth_info?
+    };
+    server_handle.stop(true).await;
+
+    let auth_info_json = serde_json::to_string_pretty(&auth_info)?;
+    std::fs::write(auth_file, auth_info_json)?;
+    info!("Onboarding completed, tokens stored");
+    Ok(auth_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::{test, web::Data, App, http::StatusCode};
+
+    use super::{authorization_callback, index, OnboardingState, OnboardingCredentials, PendingAuthorization};
+
+    #[actix_web::test]
+    async fn redirect_uri_is_escaped_on_the_page() {
+        let (sender, _receiver) = async_channel::bounded(1);
+        let state = Data::new(OnboardingState {
+            redirect_uri: "http://bridge.local/<script>alert(\"x\")</script>&/onboarding/callback".to_string(),
+            pending: Mutex::new(None),
+            completed: sender
+        });
+        let app = test::init_service(App::new().app_data(state).service(index)).await;
+
+        let page = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("http://bridge.local/&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;&amp;/onboarding/callback"));
+    }
+
+    #[actix_web::test]
+    async fn callback_with_foreign_state_is_rejected() {
+        let (sender, receiver) = async_channel::bounded(1);
+        let credentials = OnboardingCredentials { client_id: "id".to_string(), client_secret: "secret".to_string(), subscription_key: "key".to_string() };
+        let state = Data::new(OnboardingState {
+            redirect_uri: "http://localhost:8080/onboarding/callback".to_string(),
+            pending: Mutex::new(Some(PendingAuthorization { credentials, state: "expected".to_string() })),
+            completed: sender
+        });
+        let app = test::init_service(App::new().app_data(state).service(authorization_callback)).await;
+
+        for query in ["code=abc", "code=abc&state=forged"] {
+            let request = test::TestRequest::get().uri(&format!("/onboarding/callback?{}", query)).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(receiver.is_empty());
+    }
+}
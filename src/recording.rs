@@ -0,0 +1,162 @@
+use std::{io::Write, rc::Rc, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use tracing::{info, warn, error};
+use smarther::model::C2CEvents;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Context, ReplayArgs, CachedTopology, load_configuration, backend::FakeBackend, mqtt::mqtt_status_publisher};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedEvents {
+    timestamp: DateTime<Utc>,
+    plant_id: String,
+    events: serde_json::Value,
+}
+
+/// Appends raw webhook bodies to a JSON-lines file when recording is configured.
+pub(crate) struct EventRecorder {
+    file: Option<String>,
+    lock: Mutex<()>,
+}
+
+impl EventRecorder {
+    pub fn new(file: Option<String>) -> Self {
+        Self { file, lock: Mutex::new(()) }
+    }
+
+    pub fn record(&self, plant_id: &str, events: &serde_json::Value) -> anyhow::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let record = RecordedEvents {
+            timestamp: Utc::now(),
+            plant_id: plant_id.to_string(),
+            events: events.clone()
+        };
+        let line = serde_json::to_string(&record)?;
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(file)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+fn load_recording(file: &str) -> anyhow::Result<Vec<RecordedEvents>> {
+    let content = std::fs::read_to_string(file)?;
+    let mut records = vec!();
+    for (line_number, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(err) => warn!("Skipping line {} of {}: {}", line_number + 1, file, err)
+        }
+    }
+    Ok(records)
+}
+
+async fn feed_recording(context: &Context, records: Vec<RecordedEvents>, original_timing: bool) {
+    let mut previous_timestamp: Option<DateTime<Utc>> = None;
+    for record in records {
+        if let (true, Some(previous_timestamp)) = (original_timing, previous_timestamp) {
+            if let Ok(delay) = (record.timestamp - previous_timestamp).to_std() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        previous_timestamp = Some(record.timestamp);
+
+        let events: C2CEvents = match serde_json::from_value(record.events) {
+            Ok(events) => events,
+            Err(err) => {
+                error!("Failed to parse events recorded at {}: {}", record.timestamp.to_rfc3339(), err);
+                continue;
+            }
+        };
+
+        info!("Replaying events for plant {} recorded at {}", record.plant_id, record.timestamp.to_rfc3339());
+        for event in events.0 {
+            if context.status_updates.0.send(event.data).await.is_err() {
+                error!("Failed to send status update to MQTT handler");
+            }
+        }
+    }
+
+    // The MQTT publisher sends what is left in the queue before disconnecting
+    context.status_updates.0.close();
+}
+
+/// Publishes a recorded file to MQTT next to a running bridge, without taking over its session or topics.
+pub(crate) async fn replay(args: &ReplayArgs, topology_file: &str, configuration_file: &str) -> anyhow::Result<()> {
+    let configuration = load_configuration(configuration_file)?;
+    let topology_cache: CachedTopology = serde_json::from_str(&std::fs::read_to_string(topology_file)?)?;
+    let records = load_recording(&args.file)?;
+    info!("Replaying {} recorded webhook calls from {}", records.len(), args.file);
+
+    // Offline backend, the replay never talks to the Smarther cloud
+    let mut backend = FakeBackend::default();
+    for plant in &topology_cache.plants {
+        backend = backend.with_plant(plant.clone());
+    }
+    let auth_file = std::env::temp_dir().join("smarther-bridge-replay-tokens.json").to_string_lossy().to_string();
+    let context = Context::new(configuration, topology_cache, FakeBackend::authorization(), auth_file, Rc::new(backend));
+
+    let cancellation_token = CancellationToken::new();
+    tokio::join!(
+        mqtt_status_publisher(&context, cancellation_token.clone()),
+        async {
+            feed_recording(&context, records, args.original_timing).await;
+            cancellation_token.cancel();
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use smarther::model::ModuleStatus;
+
+    use crate::{backend::fixtures::{self, PLANT_ID, MODULE_ID}, mqtt::status_message};
+
+    use super::{EventRecorder, load_recording, feed_recording};
+
+    fn c2c_events(temperature: f64) -> serde_json::Value {
+        serde_json::json!([{
+            "data": fixtures::module_status_json(PLANT_ID, MODULE_ID, temperature),
+            "eventTime": "2023-04-10T10:00:00Z",
+            "eventType": "cloud2cloud",
+            "id": "event-1",
+            "subject": "smarther"
+        }])
+    }
+
+    #[tokio::test]
+    async fn recorded_events_are_replayed_as_status_updates() {
+        let file = std::env::temp_dir().join("smarther-bridge-test-recording.jsonl").to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&file);
+        let recorder = EventRecorder::new(Some(file.clone()));
+        recorder.record(PLANT_ID, &c2c_events(19.5)).unwrap();
+        writeln!(std::fs::OpenOptions::new().append(true).open(&file).unwrap(), "not a record").unwrap();
+        recorder.record(PLANT_ID, &c2c_events(21.0)).unwrap();
+
+        let context = fixtures::context(fixtures::backend());
+        feed_recording(&context, load_recording(&file).unwrap(), false).await;
+
+        let summary = |status: &ModuleStatus| {
+            let (topic, summary) = status_message(&context, &status.chronothermostats[0]).unwrap();
+            (topic, serde_json::to_value(summary).unwrap())
+        };
+        let mut summaries = vec!();
+        while let Ok(status) = context.status_updates.1.recv().await {
+            summaries.push(summary(&status));
+        }
+        let expected: Vec<_> = [19.5, 21.0].iter()
+            .map(|temperature| summary(&fixtures::module_status(PLANT_ID, MODULE_ID, *temperature)))
+            .collect();
+        assert_eq!(summaries, expected);
+        assert!(summaries.iter().all(|(topic, _)| topic == "smarther/home/living-room/status"));
+    }
+}
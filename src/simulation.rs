@@ -0,0 +1,304 @@
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use async_trait::async_trait;
+use tracing::{info, error};
+use smarther::{model::{PlantDetail, ModuleStatus, SetStatusRequest, SubscriptionInfo}, AuthorizationInfo};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Context, backend::{ThermostatBackend, ThermostatSnapshot}};
+
+const SIMULATION_TICK_SECONDS: u64 = 10;
+const AMBIENT_TEMPERATURE: f64 = 16.0;
+const PROTECTION_SET_POINT: f64 = 7.0;
+// Degrees per hour gained while heating and lost towards ambient otherwise
+const HEATING_RATE: f64 = 1.5;
+const COOLING_RATE: f64 = 0.5;
+const HYSTERESIS: f64 = 0.2;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub(crate) struct SimulatedThermostat {
+    plant_id: String,
+    #[serde(default = "SimulatedThermostat::default_plant_name")]
+    plant_name: String,
+    module_id: String,
+    name: String,
+    #[serde(default = "SimulatedThermostat::default_temperature")]
+    temperature: f64,
+    #[serde(default = "SimulatedThermostat::default_set_point")]
+    set_point: f64,
+    #[serde(default = "SimulatedThermostat::default_humidity")]
+    humidity: f64,
+}
+
+impl SimulatedThermostat {
+    fn default_plant_name() -> String {
+        "Simulated plant".to_string()
+    }
+
+    fn default_temperature() -> f64 {
+        18.0
+    }
+
+    fn default_set_point() -> f64 {
+        20.0
+    }
+
+    fn default_humidity() -> f64 {
+        50.0
+    }
+}
+
+#[derive(Debug, Clone)]
+struct VirtualThermostat {
+    definition: SimulatedThermostat,
+    temperature: f64,
+    set_point: f64,
+    mode: String,
+    heating: bool,
+}
+
+impl VirtualThermostat {
+    fn new(definition: &SimulatedThermostat) -> Self {
+        let mut thermostat = Self {
+            definition: definition.clone(),
+            temperature: definition.temperature,
+            set_point: definition.set_point,
+            mode: "AUTOMATIC".to_string(),
+            heating: false
+        };
+        thermostat.update_load_state();
+        thermostat
+    }
+
+    fn effective_set_point(&self) -> Option<f64> {
+        match self.mode.as_str() {
+            "OFF" => None,
+            "PROTECTION" => Some(PROTECTION_SET_POINT),
+            _ => Some(self.set_point)
+        }
+    }
+
+    fn update_load_state(&mut self) {
+        self.heating = match self.effective_set_point() {
+            Some(set_point) if self.temperature < set_point - HYSTERESIS => true,
+            Some(set_point) if self.temperature > set_point + HYSTERESIS => false,
+            Some(_) => self.heating,
+            None => false
+        };
+    }
+
+    fn advance(&mut self, elapsed: Duration) {
+        let hours = elapsed.as_secs_f64() / 3600.0;
+        if self.heating {
+            self.temperature += HEATING_RATE * hours;
+        } else if self.temperature > AMBIENT_TEMPERATURE {
+            self.temperature = (self.temperature - COOLING_RATE * hours).max(AMBIENT_TEMPERATURE);
+        }
+        self.update_load_state();
+    }
+
+    fn apply(&mut self, request: &SetStatusRequest) -> anyhow::Result<()> {
+        let request = serde_json::to_value(request)?;
+        if let Some(mode) = request.get("mode").and_then(|mode| mode.as_str()) {
+            self.mode = mode.to_uppercase();
+        }
+        if let Some(set_point) = request.pointer("/setPoint/value") {
+            let set_point = match set_point {
+                serde_json::Value::String(value) => value.parse::<f64>()?,
+                value => value.as_f64().ok_or(anyhow!("Invalid set point {}", value))?
+            };
+            self.set_point = set_point;
+        }
+        self.update_load_state();
+        Ok(())
+    }
+
+    fn status(&self) -> anyhow::Result<ModuleStatus> {
+        ThermostatSnapshot {
+            mode: &self.mode,
+            load_state: if self.heating { "ACTIVE" } else { "INACTIVE" },
+            set_point: self.effective_set_point().unwrap_or(self.set_point),
+            temperature: self.temperature,
+            humidity: self.definition.humidity,
+            ..ThermostatSnapshot::new(&self.definition.plant_id, &self.definition.module_id, Utc::now())
+        }.to_module_status()
+    }
+}
+
+/// Backend made of virtual chronothermostats defined in the configuration.
+pub(crate) struct SimulatedBackend {
+    thermostats: Mutex<BTreeMap<(String, String), VirtualThermostat>>,
+    changed: Notify,
+}
+
+impl SimulatedBackend {
+    pub fn new(definitions: &[SimulatedThermostat]) -> anyhow::Result<Self> {
+        if definitions.is_empty() {
+            return Err(anyhow!("No simulated_thermostats defined in the configuration"));
+        }
+
+        let thermostats = definitions.iter()
+            .map(|definition| ((definition.plant_id.clone(), definition.module_id.clone()), VirtualThermostat::new(definition)))
+            .collect();
+        Ok(Self { thermostats: Mutex::new(thermostats), changed: Notify::new() })
+    }
+
+    pub fn plants(&self) -> anyhow::Result<Vec<PlantDetail>> {
+        let thermostats = self.thermostats.lock().unwrap();
+        let mut plants: BTreeMap<&str, serde_json::Value> = BTreeMap::new();
+        for thermostat in thermostats.values() {
+            let definition = &thermostat.definition;
+            let plant = plants.entry(definition.plant_id.as_str()).or_insert_with(|| serde_json::json!({
+                "id": definition.plant_id,
+                "name": definition.plant_name,
+                "modules": []
+            }));
+            if let Some(modules) = plant["modules"].as_array_mut() {
+                modules.push(serde_json::json!({ "device": "chronothermostat", "id": definition.module_id, "name": definition.name }));
+            }
+        }
+
+        plants.into_values()
+            .map(|plant| Ok(serde_json::from_value(plant)?))
+            .collect()
+    }
+
+    fn advance(&self, elapsed: Duration) -> Vec<ModuleStatus> {
+        let mut thermostats = self.thermostats.lock().unwrap();
+        thermostats.values_mut()
+            .filter_map(|thermostat| {
+                thermostat.advance(elapsed);
+                thermostat.status().map_err(|err| error!("Failed to build simulated status: {}", err)).ok()
+            })
+            .collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl ThermostatBackend for SimulatedBackend {
+    async fn refresh_token(&self, auth_info: &AuthorizationInfo) -> anyhow::Result<AuthorizationInfo> {
+        Ok(auth_info.clone())
+    }
+
+    async fn get_plants(&self, _auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<String>> {
+        Ok(self.plants()?.into_iter().map(|plant| plant.id).collect())
+    }
+
+    async fn get_topology(&self, _auth_info: &AuthorizationInfo, plant_id: &str) -> anyhow::Result<PlantDetail> {
+        self.plants()?.into_iter()
+            .find(|plant| plant.id == plant_id)
+            .ok_or(anyhow!("Unknown plant {}", plant_id))
+    }
+
+    async fn get_device_status(&self, _auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str) -> anyhow::Result<ModuleStatus> {
+        self.thermostats.lock().unwrap()
+            .get(&(plant_id.to_string(), module_id.to_string()))
+            .ok_or(anyhow!("Unknown module {} in plant {}", module_id, plant_id))?
+            .status()
+    }
+
+    async fn set_device_status(&self, _auth_info: &AuthorizationInfo, plant_id: &str, module_id: &str, request: SetStatusRequest) -> anyhow::Result<()> {
+        self.thermostats.lock().unwrap()
+            .get_mut(&(plant_id.to_string(), module_id.to_string()))
+            .ok_or(anyhow!("Unknown module {} in plant {}", module_id, plant_id))?
+            .apply(&request)?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    async fn get_webhooks(&self, _auth_info: &AuthorizationInfo) -> anyhow::Result<Vec<SubscriptionInfo>> {
+        Ok(vec!())
+    }
+
+    async fn register_webhook(&self, _auth_info: &AuthorizationInfo, plant_id: &str, endpoint_url: String) -> anyhow::Result<SubscriptionInfo> {
+        Ok(SubscriptionInfo {
+            plant_id: Some(plant_id.to_string()),
+            subscription_id: format!("simulated-{}", plant_id),
+            endpoint_url
+        })
+    }
+
+    async fn unregister_webhook(&self, _auth_info: &AuthorizationInfo, _plant_id: &str, _subscription_id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Advances the virtual thermostats and emits their status as if it came from the cloud.
+pub(crate) async fn simulation_handler(context: &Context, backend: &SimulatedBackend, cancellation_token: CancellationToken) {
+    info!("Simulating {} thermostats", backend.thermostats.lock().unwrap().len());
+    let mut last_tick = tokio::time::Instant::now();
+    loop {
+        for status in backend.advance(last_tick.elapsed()) {
+            if context.status_updates.0.send(status).await.is_err() {
+                error!("Failed to send status update to MQTT handler");
+            }
+        }
+        last_tick = tokio::time::Instant::now();
+
+        tokio::select! {
+            _ = cancellation_token.cancelled() => { break; },
+            _ = tokio::time::sleep(Duration::from_secs(SIMULATION_TICK_SECONDS)) => {},
+            _ = backend.changed.notified() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backend::{FakeBackend, ThermostatBackend};
+
+    use super::{SimulatedThermostat, SimulatedBackend, VirtualThermostat};
+
+    fn definition(temperature: f64, set_point: f64) -> SimulatedThermostat {
+        SimulatedThermostat {
+            plant_id: "plant-1".to_string(),
+            plant_name: SimulatedThermostat::default_plant_name(),
+            module_id: "module-1".to_string(),
+            name: "Living Room".to_string(),
+            temperature,
+            set_point,
+            humidity: SimulatedThermostat::default_humidity()
+        }
+    }
+
+    #[test]
+    fn load_switches_only_outside_the_hysteresis_band() {
+        let mut thermostat = VirtualThermostat::new(&definition(19.0, 20.0));
+        assert!(thermostat.heating);
+
+        // Heats up to the set point, still inside the band
+        thermostat.advance(Duration::from_secs(40 * 60));
+        assert!(thermostat.heating);
+        thermostat.advance(Duration::from_secs(20 * 60));
+        assert!(!thermostat.heating);
+
+        // Cools back down to the set point, then below the band
+        thermostat.advance(Duration::from_secs(3600));
+        assert!(!thermostat.heating);
+        thermostat.advance(Duration::from_secs(3600));
+        assert!(thermostat.heating);
+    }
+
+    #[tokio::test]
+    async fn set_command_changes_the_simulated_thermostat() {
+        let backend = SimulatedBackend::new(&[definition(21.0, 20.0)]).unwrap();
+        let request = serde_json::from_value(serde_json::json!({
+            "function": "HEATING",
+            "mode": "MANUAL",
+            "setPoint": { "value": "23.5", "unit": "C" }
+        })).unwrap();
+
+        backend.set_device_status(&FakeBackend::authorization(), "plant-1", "module-1", request).await.unwrap();
+
+        let thermostats = backend.thermostats.lock().unwrap();
+        let thermostat = &thermostats[&("plant-1".to_string(), "module-1".to_string())];
+        assert_eq!(thermostat.mode, "MANUAL");
+        assert_eq!(thermostat.set_point, 23.5);
+        assert!(thermostat.heating);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{cell::Cell, future::Future, time::{Duration, Instant}};
+
+use tracing::{info, warn, error};
+use sd_notify::NotifyState;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::Context;
+
+// A quiet event loop is not a wedged one, it wakes up at least every keepalive or reconnection delay
+const MQTT_STALL_KEEPALIVES: u64 = 3;
+const WEBHOOK_PROBE_SECONDS: u64 = 2;
+const STATUS_INTERVAL_SECONDS: u64 = 30;
+// Onboarding waits for a human, the start timeout is pushed back for as long as it takes
+const STARTUP_EXTEND_SECONDS: u32 = 90;
+const STARTUP_EXTEND_INTERVAL_SECONDS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WebhookState {
+    Pending,
+    Disabled,
+    Registered(usize),
+    Failed
+}
+
+/// Liveness of the long running handlers, reported to systemd.
+pub(crate) struct ServiceHealth {
+    mqtt_connected: Cell<bool>,
+    mqtt_last_poll: Cell<Instant>,
+    webhooks: Cell<WebhookState>,
+    changed: Notify,
+}
+
+impl ServiceHealth {
+    pub fn new() -> Self {
+        Self {
+            mqtt_connected: Cell::new(false),
+            mqtt_last_poll: Cell::new(Instant::now()),
+            webhooks: Cell::new(WebhookState::Pending),
+            changed: Notify::new()
+        }
+    }
+
+    pub fn record_mqtt_poll(&self, connected: Option<bool>) {
+        self.mqtt_last_poll.set(Instant::now());
+        if let Some(connected) = connected {
+            if self.mqtt_connected.replace(connected) != connected {
+                self.changed.notify_one();
+            }
+        }
+    }
+
+    pub fn set_webhooks(&self, state: WebhookState) {
+        if self.webhooks.replace(state) != state {
+            self.changed.notify_one();
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.mqtt_connected.get() && self.webhooks.get() != WebhookState::Pending
+    }
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        error!("Failed to notify systemd: {}", err);
+    }
+}
+
+fn status_line(context: &Context) -> String {
+    let mqtt = if context.health.mqtt_connected.get() { "MQTT connected" } else { "MQTT disconnected" };
+    let webhooks = match context.health.webhooks.get() {
+        WebhookState::Pending => "registering webhooks".to_string(),
+        WebhookState::Disabled => "webhooks not configured".to_string(),
+        WebhookState::Registered(count) => format!("{} webhooks registered", count),
+        WebhookState::Failed => "webhook registration failed".to_string()
+    };
+    format!("{}; {}; {}", mqtt, context.auth_state.borrow().summary(), webhooks)
+}
+
+async fn is_alive(context: &Context) -> bool {
+    let configuration = context.configuration();
+    let last_poll = context.health.mqtt_last_poll.get().elapsed();
+    if last_poll > Duration::from_secs(MQTT_STALL_KEEPALIVES * configuration.mqtt_keep_alive_seconds + configuration.mqtt_reconnect_max_delay_seconds) {
+        warn!("MQTT event loop not polled for {} seconds", last_poll.as_secs());
+        return false;
+    }
+
+    if configuration.webhook_endpoint.is_some() {
+        let probe = tokio::net::TcpStream::connect((configuration.listen_host.as_str(), configuration.listen_port));
+        if !matches!(tokio::time::timeout(Duration::from_secs(WEBHOOK_PROBE_SECONDS), probe).await, Ok(Ok(_))) {
+            warn!("Webhook server on {}:{} is not accepting connections", configuration.listen_host, configuration.listen_port);
+            return false;
+        }
+    }
+    true
+}
+
+/// Runs a startup phase that comes before the notifier, such as onboarding or topology discovery,
+/// extending the start timeout and pinging the watchdog so that systemd does not kill the bridge meanwhile.
+pub(crate) async fn notify_startup_phase<T>(status: &str, phase: impl Future<Output = T>) -> T {
+    notify(&[NotifyState::Status(status)]);
+    tokio::pin!(phase);
+    let mut ticks = tokio::time::interval(Duration::from_secs(STARTUP_EXTEND_INTERVAL_SECONDS));
+    loop {
+        tokio::select! {
+            result = &mut phase => return result,
+            _ = ticks.tick() => notify(&[NotifyState::ExtendTimeoutUsec(STARTUP_EXTEND_SECONDS * 1_000_000), NotifyState::Watchdog])
+        }
+    }
+}
+
+/// Sends READY, STATUS and WATCHDOG notifications, a no-op when not started by systemd.
+pub(crate) async fn systemd_notifier(context: &Context, cancellation_token: CancellationToken) {
+    let mut watchdog_usec = 0;
+    let watchdog_interval = sd_notify::watchdog_enabled(false, &mut watchdog_usec)
+        .then(|| Duration::from_micros(watchdog_usec) / 2);
+    if let Some(interval) = watchdog_interval {
+        info!("Systemd watchdog enabled, pinging every {} ms", interval.as_millis());
+    }
+
+    let mut ticks = tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(STATUS_INTERVAL_SECONDS)));
+    let mut ready = false;
+    let mut last_status = String::new();
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => { break; },
+            _ = context.health.changed.notified() => {},
+            _ = ticks.tick() => {
+                if watchdog_interval.is_some() && is_alive(context).await {
+                    notify(&[NotifyState::Watchdog]);
+                }
+            }
+        }
+
+        if !ready && context.health.is_ready() {
+            info!("Bridge ready");
+            notify(&[NotifyState::Ready]);
+            ready = true;
+        }
+
+        let status = status_line(context);
+        if status != last_status {
+            notify(&[NotifyState::Status(&status)]);
+            last_status = status;
+        }
+    }
+
+    notify(&[NotifyState::Stopping]);
+}
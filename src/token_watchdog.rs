@@ -1,11 +1,15 @@
 use std::time::Duration;
 
-use smarther::SmartherApi;
+use chrono::{DateTime, Utc};
+use tracing::{error, warn};
+use smarther::AuthorizationInfo;
 use tokio_util::sync::CancellationToken;
 
-use crate::{Context, refresh_token_if_needed};
+use crate::{Context, refresh_token};
 
-const REFRESH_TOKEN_DAYS: u64 = 85;
+const REFRESH_TOKEN_DAYS: i64 = 85;
+// Legrand refresh tokens are rotated on every refresh and last 90 days
+const REFRESH_TOKEN_LIFETIME_DAYS: i64 = 90;
 const REFRESH_TOKEN_FAIL_INTERVAL_SECONDS: u64 = 60*5;
 
 enum BreakType {
@@ -14,6 +18,71 @@ enum BreakType {
     None
 }
 
+/// Health of the refresh token, the access token is short lived and refreshed on demand.
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub(crate) struct AuthState {
+    valid: bool,
+    expires_at: DateTime<Utc>,
+    access_token_expires_at: DateTime<Utc>,
+    last_refresh: Option<DateTime<Utc>>,
+    consecutive_failures: u32,
+    reauth_required: bool,
+}
+
+impl AuthState {
+    /// `stored_at` is when the tokens were last written, the refresh token expiry is estimated from it.
+    pub fn new(auth_info: &AuthorizationInfo, stored_at: DateTime<Utc>) -> Self {
+        let mut state = Self {
+            valid: false,
+            expires_at: stored_at + chrono::Duration::days(REFRESH_TOKEN_LIFETIME_DAYS),
+            access_token_expires_at: auth_info.expires_on,
+            last_refresh: None,
+            consecutive_failures: 0,
+            reauth_required: false
+        };
+        state.update_validity();
+        state
+    }
+
+    fn update_validity(&mut self) {
+        self.valid = self.expires_at > Utc::now();
+        self.reauth_required = !self.valid;
+    }
+
+    /// When the token refresher has to renew the refresh token.
+    pub fn refresh_due(&self) -> DateTime<Utc> {
+        self.expires_at - chrono::Duration::days(REFRESH_TOKEN_LIFETIME_DAYS - REFRESH_TOKEN_DAYS)
+    }
+
+    /// Records a successful refresh, on demand or from the token refresher.
+    pub fn record_success(&mut self, previous: &AuthorizationInfo, current: &AuthorizationInfo) {
+        if previous.expires_on != current.expires_on {
+            let now = Utc::now();
+            self.last_refresh = Some(now);
+            self.expires_at = now + chrono::Duration::days(REFRESH_TOKEN_LIFETIME_DAYS);
+        }
+        self.access_token_expires_at = current.expires_on;
+        self.consecutive_failures = 0;
+        self.update_validity();
+    }
+
+    /// Records a failure of the token refresher, on demand refreshes are retried by the next command.
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        self.update_validity();
+    }
+
+    pub fn summary(&self) -> String {
+        if self.reauth_required {
+            format!("refresh token expired on {}, run setup", self.expires_at.to_rfc3339())
+        } else if self.consecutive_failures > 0 {
+            format!("token refresh failing ({} attempts), refresh token expires {}", self.consecutive_failures, self.expires_at.to_rfc3339())
+        } else {
+            format!("refresh token valid until {}", self.expires_at.to_rfc3339())
+        }
+    }
+}
+
 async fn wait_with_cancellation(context: &Context, cancellation_token: &CancellationToken, delay: Duration) -> BreakType {
     tokio::select! {
         _ = tokio::time::sleep(delay) => BreakType::None,
@@ -23,23 +92,33 @@ async fn wait_with_cancellation(context: &Context, cancellation_token: &Cancella
 }
 
 pub(crate) async fn token_refresher(context: &Context, cancellation_token: CancellationToken) {
-    let refresh_max_interval = Duration::from_secs(86400 * REFRESH_TOKEN_DAYS);
     let refresh_fail_max_interval = Duration::from_secs(REFRESH_TOKEN_FAIL_INTERVAL_SECONDS);
-    
+
     'outer : while !cancellation_token.is_cancelled() {
-        match wait_with_cancellation(context, &cancellation_token, refresh_max_interval).await {
+        let refresh_due = context.auth_state.borrow().refresh_due();
+        let refresh_delay = (refresh_due - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+        match wait_with_cancellation(context, &cancellation_token, refresh_delay).await {
             BreakType::Continue => { continue; }
             BreakType::Break => { break 'outer; }
             BreakType::None => {}
         }
 
-        let client = SmartherApi::default();
         loop {
-            if let Ok(auth_info) = refresh_token_if_needed(&client, context.auth_info.clone().into_inner(), &context.auth_file).await {
-                context.auth_info.replace(auth_info.clone());
-                break;
+            let previous_auth_info = context.auth_info.clone().into_inner();
+            // Forced, the access token may still be valid while the refresh token is about to expire
+            match refresh_token(context.backend.as_ref(), &previous_auth_info, &context.auth_file).await {
+                Ok(auth_info) => {
+                    context.auth_info.replace(auth_info.clone());
+                    context.update_auth_state(|state| state.record_success(&previous_auth_info, &auth_info)).await;
+                    break;
+                },
+                Err(err) => {
+                    error!("Failed to refresh token: {}", err);
+                    context.update_auth_state(|state| state.record_failure()).await;
+                    warn!("Retrying token refresh in {} seconds", REFRESH_TOKEN_FAIL_INTERVAL_SECONDS);
+                }
             }
-            
+
             match wait_with_cancellation(context, &cancellation_token, refresh_fail_max_interval).await {
                 BreakType::Continue => { continue 'outer; }
                 BreakType::Break => { break 'outer; }
@@ -48,3 +127,52 @@ pub(crate) async fn token_refresher(context: &Context, cancellation_token: Cance
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Utc, Duration};
+
+    use crate::backend::FakeBackend;
+
+    use super::{AuthState, REFRESH_TOKEN_DAYS, REFRESH_TOKEN_LIFETIME_DAYS};
+
+    #[test]
+    fn expired_access_token_alone_keeps_the_state_valid() {
+        let mut auth_info = FakeBackend::authorization();
+        auth_info.expires_on = Utc::now() - Duration::minutes(1);
+        let mut state = AuthState::new(&auth_info, Utc::now());
+        assert!(state.valid);
+
+        state.record_failure();
+        assert_eq!(state.consecutive_failures, 1);
+        assert!(!state.reauth_required);
+    }
+
+    #[test]
+    fn refresh_token_past_its_lifetime_requires_reauth_until_a_refresh_succeeds() {
+        let auth_info = FakeBackend::authorization();
+        let mut state = AuthState::new(&auth_info, Utc::now() - Duration::days(REFRESH_TOKEN_LIFETIME_DAYS + 1));
+        assert!(state.refresh_due() < Utc::now());
+        state.record_failure();
+        assert!(state.reauth_required);
+
+        let mut refreshed = auth_info.clone();
+        refreshed.expires_on = auth_info.expires_on + Duration::hours(1);
+        state.record_success(&auth_info, &refreshed);
+        assert!(state.valid);
+        assert!(!state.reauth_required);
+        assert_eq!(state.consecutive_failures, 0);
+        assert!(state.last_refresh.is_some());
+        assert!(state.refresh_due() > Utc::now() + Duration::days(REFRESH_TOKEN_DAYS - 1));
+    }
+
+    #[test]
+    fn unchanged_tokens_do_not_change_the_state() {
+        let auth_info = FakeBackend::authorization();
+        let mut state = AuthState::new(&auth_info, Utc::now());
+        let before = state.clone();
+
+        state.record_success(&auth_info, &auth_info);
+        assert_eq!(state, before);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+
+use crate::{BridgeConfiguration, CachedTopology};
+
+const MODULE_PLACEHOLDERS: [&str; 6] = ["plant_id", "plant_name", "plant_alias", "module_id", "module_name", "module_alias"];
+// Used for placeholders a command topic did not carry, when answering on the result topic
+const UNKNOWN_VALUE: &str = "unknown";
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub(crate) struct TopicTemplates {
+    #[serde(default = "TopicTemplates::default_status")]
+    status: String,
+    #[serde(default = "TopicTemplates::default_command")]
+    command: String,
+    #[serde(default = "TopicTemplates::default_result")]
+    result: String,
+    #[serde(default = "TopicTemplates::default_availability")]
+    availability: String,
+    #[serde(default = "TopicTemplates::default_auth")]
+    auth: String,
+}
+
+impl Default for TopicTemplates {
+    fn default() -> Self {
+        Self {
+            status: TopicTemplates::default_status(),
+            command: TopicTemplates::default_command(),
+            result: TopicTemplates::default_result(),
+            availability: TopicTemplates::default_availability(),
+            auth: TopicTemplates::default_auth()
+        }
+    }
+}
+
+impl TopicTemplates {
+    /// Topics named after the plant and module ids, the only ones before aliases were introduced.
+    pub fn by_id() -> Self {
+        Self {
+            status: "{base}/{plant_id}/{module_id}/status".to_string(),
+            command: "{base}/{plant_id}/{module_id}/set_status".to_string(),
+            result: "{base}/{plant_id}/{module_id}/result".to_string(),
+            ..TopicTemplates::default()
+        }
+    }
+
+    fn default_status() -> String {
+        "{base}/{plant_alias}/{module_alias}/status".to_string()
+    }
+
+    fn default_command() -> String {
+        "{base}/{plant_alias}/{module_alias}/set_status".to_string()
+    }
+
+    fn default_result() -> String {
+        "{base}/{plant_alias}/{module_alias}/result".to_string()
+    }
+
+    fn default_availability() -> String {
+        "{base}/bridge/availability".to_string()
+    }
+
+    fn default_auth() -> String {
+        "{base}/bridge/auth".to_string()
+    }
+}
+
+/// Lowercase words joined by `-`, so that "Living Room" becomes "living-room".
+fn slug(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Configured alias of a plant or module, or the slug of its name, or its id when the name has no letters
+/// or digits or when a sibling (another plant, or a module of the same plant) would end up with the same slug.
+fn alias<'a>(aliases: &BTreeMap<String, String>, id: &str, name: &str, siblings: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    if let Some(alias) = aliases.get(id) {
+        return alias.clone();
+    }
+    let name_slug = slug(name);
+    let taken = siblings
+        .filter(|(sibling_id, _)| *sibling_id != id)
+        .any(|(sibling_id, sibling_name)| aliases.get(sibling_id).cloned().unwrap_or_else(|| slug(sibling_name)) == name_slug);
+    if name_slug.is_empty() || taken {
+        id.to_string()
+    } else {
+        name_slug
+    }
+}
+
+/// Configured aliases shared by two plants, or by two modules of the same plant, which would make their
+/// topics collide. Aliases of ids missing from the topology cannot collide yet.
+pub(crate) fn duplicate_aliases(topology: &CachedTopology, aliases: &BTreeMap<String, String>) -> Vec<String> {
+    let plants = topology.plants.iter().map(|plant| (None, plant.id.as_str()));
+    let modules = topology.plants.iter()
+        .flat_map(|plant| plant.modules.iter().map(move |module| (Some(plant.id.as_str()), module.id.as_str())));
+    let mut owners = BTreeMap::new();
+    let mut problems = vec!();
+    for (plant_id, id) in plants.chain(modules) {
+        let Some(alias) = aliases.get(id) else {
+            continue;
+        };
+        if let Some(other_id) = owners.insert((plant_id, alias.as_str()), id) {
+            problems.push(match plant_id {
+                None => format!("alias {} is used by both plants {} and {}", alias, other_id, id),
+                Some(plant_id) => format!("alias {} is used by both modules {} and {} of plant {}", alias, other_id, id, plant_id)
+            });
+        }
+    }
+    problems
+}
+
+/// Plant and module a topic is rendered for.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TopicModule {
+    pub plant_id: String,
+    pub plant_name: String,
+    pub plant_alias: String,
+    pub module_id: String,
+    pub module_name: String,
+    pub module_alias: String,
+}
+
+impl TopicModule {
+    /// Looks the names up in the topology, modules missing from it are named after their ids.
+    pub fn new(topology: &CachedTopology, aliases: &BTreeMap<String, String>, plant_id: &str, module_id: &str) -> Self {
+        let plant = topology.plants.iter().find(|plant| plant.id == plant_id);
+        let module = plant.and_then(|plant| plant.modules.iter().find(|module| module.id == module_id));
+        let plant_name = plant.map_or(plant_id, |plant| plant.name.as_str());
+        let module_name = module.map_or(module_id, |module| module.name.as_str());
+        let other_plants = topology.plants.iter().map(|plant| (plant.id.as_str(), plant.name.as_str()));
+        let other_modules = plant.into_iter().flat_map(|plant| plant.modules.iter().map(|module| (module.id.as_str(), module.name.as_str())));
+        Self {
+            plant_id: plant_id.to_string(),
+            plant_name: plant_name.to_string(),
+            plant_alias: alias(aliases, plant_id, plant_name, other_plants),
+            module_id: module_id.to_string(),
+            module_name: module_name.to_string(),
+            module_alias: alias(aliases, module_id, module_name, other_modules)
+        }
+    }
+
+    /// Same module with the aliases replaced by the ids, for the id-based topics.
+    fn by_id(&self) -> Self {
+        Self { plant_alias: self.plant_id.clone(), module_alias: self.module_id.clone(), ..self.clone() }
+    }
+
+    fn value(&self, placeholder: &str) -> Option<&str> {
+        match placeholder {
+            "plant_id" => Some(&self.plant_id),
+            "plant_name" => Some(&self.plant_name),
+            "plant_alias" => Some(&self.plant_alias),
+            "module_id" => Some(&self.module_id),
+            "module_name" => Some(&self.module_name),
+            "module_alias" => Some(&self.module_alias),
+            _ => None
+        }
+    }
+}
+
+/// Keeps a value within a single topic level and free of wildcards.
+fn topic_value(value: &str) -> String {
+    value.chars().map(|c| if matches!(c, '/' | '+' | '#') { '_' } else { c }).collect()
+}
+
+/// A topic level, made of an optional placeholder surrounded by literal text.
+#[derive(Debug)]
+struct Level {
+    prefix: String,
+    placeholder: Option<String>,
+    suffix: String,
+}
+
+impl Level {
+    fn parse(level: &str, template: &str) -> anyhow::Result<Self> {
+        let literal_problem = |text: &str| text.contains(['{', '}', '+', '#']);
+        let Some(start) = level.find('{') else {
+            if literal_problem(level) {
+                return Err(anyhow!("Topic template {} contains wildcards or unbalanced braces", template));
+            }
+            return Ok(Self { prefix: level.to_string(), placeholder: None, suffix: String::new() });
+        };
+        let end = level[start..].find('}').map(|end| start + end)
+            .ok_or(anyhow!("Unclosed placeholder in topic template {}", template))?;
+        let placeholder = &level[start + 1..end];
+        if !MODULE_PLACEHOLDERS.contains(&placeholder) {
+            return Err(anyhow!("Unknown placeholder {{{}}} in topic template {}", placeholder, template));
+        }
+        let (prefix, suffix) = (&level[..start], &level[end + 1..]);
+        if literal_problem(prefix) || literal_problem(suffix) {
+            return Err(anyhow!("Topic template {} must have at most one placeholder per level and no wildcards", template));
+        }
+        Ok(Self { prefix: prefix.to_string(), placeholder: Some(placeholder.to_string()), suffix: suffix.to_string() })
+    }
+
+    fn capture<'a>(&self, level: &'a str) -> Option<&'a str> {
+        let value = level.strip_prefix(self.prefix.as_str())?.strip_suffix(self.suffix.as_str())?;
+        (!value.is_empty()).then_some(value)
+    }
+}
+
+/// Topic template with the base topic already substituted.
+#[derive(Debug)]
+struct TopicTemplate {
+    levels: Vec<Level>,
+}
+
+impl TopicTemplate {
+    fn parse(template: &str, base_topic: &str) -> anyhow::Result<Self> {
+        let levels = template.replace("{base}", base_topic)
+            .split('/')
+            .map(|level| Level::parse(level, template))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { levels })
+    }
+
+    fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.levels.iter().filter_map(|level| level.placeholder.as_deref())
+    }
+
+    fn render<'a>(&self, value: impl Fn(&str) -> Option<&'a str>) -> String {
+        self.levels.iter()
+            .map(|level| match &level.placeholder {
+                Some(placeholder) => format!("{}{}{}", level.prefix, topic_value(value(placeholder).unwrap_or(UNKNOWN_VALUE)), level.suffix),
+                None => level.prefix.clone()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Subscription filter matching every topic the template can render.
+    fn filter(&self) -> String {
+        self.levels.iter()
+            .map(|level| if level.placeholder.is_some() { "+" } else { level.prefix.as_str() })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn captures<'a>(&self, topic: &'a str) -> Option<Vec<(&str, &'a str)>> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        if levels.len() != self.levels.len() {
+            return None;
+        }
+        let mut captures = vec!();
+        for (template_level, level) in self.levels.iter().zip(levels) {
+            match &template_level.placeholder {
+                Some(placeholder) => captures.push((placeholder.as_str(), template_level.capture(level)?)),
+                None if template_level.prefix == level => {},
+                None => return None
+            }
+        }
+        Some(captures)
+    }
+}
+
+/// Module addressed by a command topic, `None` when no module of the topology renders to it.
+pub(crate) struct CommandRoute {
+    pub module: Option<TopicModule>,
+    pub result_topic: String,
+}
+
+/// Topic templates of a configuration, ready to render and match topics.
+pub(crate) struct Topics {
+    aliases: BTreeMap<String, String>,
+    status: TopicTemplate,
+    command: TopicTemplate,
+    result: TopicTemplate,
+    availability: TopicTemplate,
+    auth: TopicTemplate,
+}
+
+impl Topics {
+    pub fn new(configuration: &BridgeConfiguration) -> anyhow::Result<Self> {
+        let templates = &configuration.mqtt_topics;
+        let base_topic = &configuration.mqtt_base_topic;
+        let topics = Self {
+            aliases: configuration.aliases.clone(),
+            status: TopicTemplate::parse(&templates.status, base_topic)?,
+            command: TopicTemplate::parse(&templates.command, base_topic)?,
+            result: TopicTemplate::parse(&templates.result, base_topic)?,
+            availability: TopicTemplate::parse(&templates.availability, base_topic)?,
+            auth: TopicTemplate::parse(&templates.auth, base_topic)?
+        };
+        if topics.availability.placeholders().next().is_some() {
+            return Err(anyhow!("Availability topic template {} can only use {{base}}", templates.availability));
+        }
+        if topics.auth.placeholders().next().is_some() {
+            return Err(anyhow!("Auth topic template {} can only use {{base}}", templates.auth));
+        }
+        if !topics.command.placeholders().any(|placeholder| placeholder.starts_with("module_")) {
+            return Err(anyhow!("Command topic template {} must contain {{module_id}}, {{module_name}} or {{module_alias}}", templates.command));
+        }
+        Ok(topics)
+    }
+
+    pub fn module(&self, topology: &CachedTopology, plant_id: &str, module_id: &str) -> TopicModule {
+        TopicModule::new(topology, &self.aliases, plant_id, module_id)
+    }
+
+    fn modules<'a>(&'a self, topology: &'a CachedTopology) -> impl Iterator<Item = TopicModule> + 'a {
+        topology.plants.iter()
+            .flat_map(move |plant| plant.modules.iter().map(move |module| self.module(topology, &plant.id, &module.id)))
+    }
+
+    pub fn status(&self, module: &TopicModule) -> String {
+        self.status.render(|placeholder| module.value(placeholder))
+    }
+
+    pub fn result(&self, module: &TopicModule) -> String {
+        self.result.render(|placeholder| module.value(placeholder))
+    }
+
+    pub fn availability(&self) -> String {
+        self.availability.render(|_| None)
+    }
+
+    pub fn auth(&self) -> String {
+        self.auth.render(|_| None)
+    }
+
+    pub fn command_filter(&self) -> String {
+        self.command.filter()
+    }
+
+    /// Matches a command topic against the alias and id based topics of each module of the topology,
+    /// `None` for topics the command template does not match.
+    pub fn route_command(&self, topology: &CachedTopology, topic: &str) -> Option<CommandRoute> {
+        let captures = self.command.captures(topic)?;
+        let renders_to_topic = |module: &TopicModule| self.command.render(|placeholder| module.value(placeholder)) == topic;
+        if let Some(module) = self.modules(topology).find(|module| renders_to_topic(module) || renders_to_topic(&module.by_id())) {
+            return Some(CommandRoute { result_topic: self.result(&module), module: Some(module) });
+        }
+
+        let captured = |placeholder: &str| captures.iter().find(|(name, _)| *name == placeholder).map(|(_, value)| *value);
+        Some(CommandRoute { module: None, result_topic: self.result.render(captured) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BridgeConfiguration, CachedTopology, backend::fixtures::{self, PLANT_ID, MODULE_ID}};
+
+    use super::{Topics, TopicTemplates, slug};
+
+    fn configuration(status: &str, command: &str) -> BridgeConfiguration {
+        let mqtt_topics = TopicTemplates { status: status.to_string(), command: command.to_string(), ..TopicTemplates::default() };
+        BridgeConfiguration { mqtt_base_topic: "home/heating".to_string(), mqtt_topics, ..BridgeConfiguration::default() }
+    }
+
+    #[test]
+    fn templates_render_names() {
+        let topics = Topics::new(&configuration("{base}/{module_name}/state", "{base}/room-{module_name}/set")).unwrap();
+        let topology = CachedTopology { plants: vec!(fixtures::plant()) };
+        let module = topics.module(&topology, PLANT_ID, MODULE_ID);
+
+        assert_eq!(topics.status(&module), "home/heating/Living Room/state");
+        assert_eq!(topics.auth(), "home/heating/bridge/auth");
+        assert_eq!(topics.command_filter(), "home/heating/+/set");
+        assert_eq!(topics.route_command(&topology, "home/heating/room-Living Room/set").unwrap().module, Some(module));
+        assert!(topics.route_command(&topology, "home/heating/room-Kitchen/set").unwrap().module.is_none());
+        assert!(topics.route_command(&topology, "home/heating/Living Room/set").is_none());
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(Topics::new(&configuration("{base}/{room}/status", "{base}/{module_id}/set")).is_err());
+        assert!(Topics::new(&configuration("{base}/{plant_id}{module_id}/status", "{base}/{module_id}/set")).is_err());
+        assert!(Topics::new(&configuration("{base}/+/status", "{base}/{module_id}/set")).is_err());
+        assert!(Topics::new(&configuration("{base}/{module_id}/status", "{base}/{plant_id}/set")).is_err());
+    }
+
+    #[test]
+    fn commands_are_accepted_on_alias_and_id_topics() {
+        let mut configuration = BridgeConfiguration::default();
+        let topology = CachedTopology { plants: vec!(fixtures::plant()) };
+        let topics = Topics::new(&configuration).unwrap();
+        assert_eq!(topics.status(&topics.module(&topology, PLANT_ID, MODULE_ID)), "smarther/home/living-room/status");
+        assert!(topics.route_command(&topology, "smarther/home/living-room/set_status").unwrap().module.is_some());
+        assert!(topics.route_command(&topology, &format!("smarther/{}/{}/set_status", PLANT_ID, MODULE_ID)).unwrap().module.is_some());
+
+        configuration.aliases.insert(MODULE_ID.to_string(), "lounge".to_string());
+        let topics = Topics::new(&configuration).unwrap();
+        let route = topics.route_command(&topology, "smarther/home/lounge/set_status").unwrap();
+        assert_eq!(route.result_topic, "smarther/home/lounge/result");
+        assert!(route.module.is_some());
+    }
+
+    #[test]
+    fn modules_with_the_same_slug_fall_back_to_their_ids() {
+        let plant = serde_json::from_value(serde_json::json!({
+            "id": PLANT_ID,
+            "name": "Home",
+            "modules": [
+                { "device": "chronothermostat", "id": "module-a", "name": "Bagno 1" },
+                { "device": "chronothermostat", "id": "module-b", "name": "bagno-1" },
+                { "device": "chronothermostat", "id": "module-c", "name": "Cucina" }
+            ]
+        })).unwrap();
+        let topology = CachedTopology { plants: vec!(plant) };
+        let topics = Topics::new(&BridgeConfiguration::default()).unwrap();
+
+        assert_eq!(topics.status(&topics.module(&topology, PLANT_ID, "module-a")), "smarther/home/module-a/status");
+        assert_eq!(topics.status(&topics.module(&topology, PLANT_ID, "module-b")), "smarther/home/module-b/status");
+        assert_eq!(topics.status(&topics.module(&topology, PLANT_ID, "module-c")), "smarther/home/cucina/status");
+        let route = topics.route_command(&topology, "smarther/home/module-b/set_status").unwrap();
+        assert_eq!(route.module.unwrap().module_id, "module-b");
+        assert!(topics.route_command(&topology, "smarther/home/bagno-1/set_status").unwrap().module.is_none());
+    }
+
+    #[test]
+    fn names_are_slugified() {
+        assert_eq!(slug("Living Room"), "living-room");
+        assert_eq!(slug("  Bagno / Camera 2 "), "bagno-camera-2");
+        assert_eq!(slug("Età"), "età");
+    }
+}
@@ -1,35 +1,97 @@
-use actix_web::{post, web::{Data, self}, HttpServer, App, error, HttpResponse, middleware::Logger};
+use actix_web::{post, web::{Data, self}, HttpServer, App, HttpResponse, middleware::Logger};
 use async_channel::Sender;
-use log::{error, warn, info, debug};
-use smarther::{model::{ModuleStatus, C2CEvents}, SmartherApi};
+use tracing::{error, warn, info, debug, info_span, field, Instrument, Span};
+use smarther::model::{ModuleStatus, C2CEvents, SubscriptionInfo};
 use tokio_util::sync::CancellationToken;
 
-use crate::Context;
+use crate::{Context, BridgeConfiguration, recording::EventRecorder, config::section_changed, systemd::WebhookState, logging::request_id};
 
 #[post("/smarther_bridge/{id}")]
-async fn process(path: web::Path<String>, context: Data<(Vec<String>, Sender<ModuleStatus>)>, payload: web::Json<C2CEvents>) -> &'static str {
+pub(crate) async fn process(path: web::Path<String>, context: Data<(Vec<String>, Sender<ModuleStatus>)>, recorder: Option<Data<EventRecorder>>, body: web::Bytes) -> HttpResponse {
     let plant_id = path.into_inner();
+    let span = info_span!("webhook", request_id = %request_id(), plant_id = %plant_id, module_id = field::Empty, outcome = field::Empty);
+    process_events(plant_id, context, recorder, body).instrument(span).await
+}
+
+async fn process_events(plant_id: String, context: Data<(Vec<String>, Sender<ModuleStatus>)>, recorder: Option<Data<EventRecorder>>, body: web::Bytes) -> HttpResponse {
+    let span = Span::current();
+    let payload = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(payload) => payload,
+        Err(err) => {
+            debug!("Failed to parse JSON: {}", err);
+            span.record("outcome", "invalid_json");
+            return HttpResponse::Conflict().finish();
+        }
+    };
+
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.record(&plant_id, &payload) {
+            error!("Failed to record webhook events: {}", err);
+        }
+    }
+
+    let payload: C2CEvents = match serde_json::from_value(payload) {
+        Ok(payload) => payload,
+        Err(err) => {
+            debug!("Failed to parse C2C events: {}", err);
+            span.record("outcome", "invalid_events");
+            return HttpResponse::Conflict().finish();
+        }
+    };
+
     let is_active_plant = context.0.iter().any(|sub| sub == &plant_id);
     if !is_active_plant {
-        return "Plant not active";
+        span.record("outcome", "inactive_plant");
+        return HttpResponse::Ok().body("Plant not active");
     }
 
+    let module_ids: Vec<String> = payload.0.iter()
+        .flat_map(|event| event.data.chronothermostats.iter())
+        .filter_map(|status| status.sender.as_ref()?.plant.as_ref().map(|plant| plant.module.id.clone()))
+        .collect();
+    span.record("module_id", module_ids.join(",").as_str());
     info!("Received status update for plant {}", plant_id);
 
     let tx = context.1.clone();
+    let mut outcome = "queued";
     for event in payload.0 {
         if tx.send(event.data).await.is_err() {
             error!("Failed to send status update to MQTT handler");
+            outcome = "queue_closed";
         }
     }
-    "OK"
+    span.record("outcome", outcome);
+    HttpResponse::Ok().body("OK")
+}
+
+pub(crate) fn webhook_url(endpoint: &str, plant_id: &str) -> String {
+    format!("{endpoint}/smarther_bridge/{plant_id}")
+}
+
+pub(crate) fn is_bridge_subscription(configuration: &BridgeConfiguration, subscription: &SubscriptionInfo) -> bool {
+    match (&configuration.webhook_endpoint, &subscription.plant_id) {
+        (Some(endpoint), Some(plant_id)) => subscription.endpoint_url == webhook_url(endpoint, plant_id),
+        _ => false
+    }
+}
+
+fn listener_settings(configuration: &BridgeConfiguration) -> (String, u16) {
+    (configuration.listen_host.clone(), configuration.listen_port)
 }
 
 pub(crate) async fn webhook_handler(context: &Context, cancellation_token: CancellationToken) {
     // Try to subscribe
-    if context.configuration.webhook_endpoint.is_none() {
-        warn!("Webhook endpoint not configured, skipping webhook handler");
-        return;
+    let mut configuration_updates = context.configuration.subscribe();
+    while configuration_updates.borrow().webhook_endpoint.is_none() {
+        warn!("Webhook endpoint not configured, webhook handler waits for a configuration reload");
+        context.health.set_webhooks(WebhookState::Disabled);
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {
+                context.status_updates.0.close();
+                return;
+            },
+            _ = configuration_updates.changed() => {}
+        }
     }
 
     tokio::join!(
@@ -38,27 +100,19 @@ pub(crate) async fn webhook_handler(context: &Context, cancellation_token: Cance
     );
 }
 
-async fn handle_subscriptions(context: &Context, cancellation_token: CancellationToken) {
-    let mut active_subscriptions = clear_active_subscriptions(context, None).await;
-
+async fn register_subscriptions(context: &Context, endpoint: &str, active_subscriptions: &mut Vec<SubscriptionInfo>) {
     if context.refresh_token_if_needed().await.is_err() {
         error!("Failed to refresh token");
+        context.health.set_webhooks(WebhookState::Failed);
         return;
     }
 
-    let client = SmartherApi::default();
-    let auth_request = client.with_authorization(context.auth_info.borrow().clone());
-    if auth_request.is_err() {
-        error!("Failed to create authorized client");
-        return;
-    }
-
-    let client = auth_request.unwrap();
+    let auth_info = context.auth_info.borrow().clone();
+    let mut registered = 0;
     for plant in &context.topology_cache.plants {
-        let endpoint = context.configuration.webhook_endpoint.clone().unwrap();
         let plant_id = plant.id.clone();
-        let endpoint_url = format!("{endpoint}/smarther_bridge/{plant_id}");
-        let subscription_info = client.register_webhook(&plant_id, endpoint_url).await;
+        let endpoint_url = webhook_url(endpoint, &plant_id);
+        let subscription_info = context.backend.register_webhook(&auth_info, &plant_id, endpoint_url).await;
         if subscription_info.is_err() {
             error!("Failed to register webhook for plant {}: {}", plant_id, subscription_info.err().unwrap());
             continue;
@@ -66,54 +120,74 @@ async fn handle_subscriptions(context: &Context, cancellation_token: Cancellatio
         let mut subscription = subscription_info.unwrap();
         subscription.plant_id = Some(plant_id);
         active_subscriptions.push(subscription);
+        registered += 1;
     }
 
-    if active_subscriptions.is_empty() {
+    if registered == 0 {
         error!("Failed to register any webhook");
+        context.health.set_webhooks(WebhookState::Failed);
         return;
     }
 
-    info!("Registered webhooks for {} plants", active_subscriptions.len());
+    info!("Registered webhooks for {} plants", registered);
+    context.health.set_webhooks(WebhookState::Registered(registered));
+}
+
+async fn handle_subscriptions(context: &Context, cancellation_token: CancellationToken) {
+    let mut configuration_updates = context.configuration.subscribe();
+    let mut registered_configuration = context.configuration();
+    // Leftovers of a previous run of this bridge, other subscriptions on the account are not ours
+    let mut active_subscriptions = clear_bridge_subscriptions(context, &registered_configuration, None).await;
 
-    //Wait for end
-    cancellation_token.cancelled().await;
+    loop {
+        registered_configuration = configuration_updates.borrow().clone();
+        match &registered_configuration.webhook_endpoint {
+            Some(endpoint) => register_subscriptions(context, endpoint, &mut active_subscriptions).await,
+            None => {
+                warn!("Webhook endpoint removed from the configuration, no webhook registered");
+                context.health.set_webhooks(WebhookState::Disabled);
+            }
+        }
 
-    info!("Unregistering webhooks...");
+        //Wait for end or for a new endpoint
+        tokio::select! {
+            _ = cancellation_token.cancelled() => { break; },
+            _ = section_changed(&mut configuration_updates, |configuration| configuration.webhook_endpoint.clone()) => {
+                info!("Webhook endpoint changed, registering webhooks again");
+                active_subscriptions = clear_bridge_subscriptions(context, &registered_configuration, Some(active_subscriptions)).await;
+            }
+        }
+    }
 
-    //Remove all subscriptions
-    clear_active_subscriptions(context, Some(active_subscriptions)).await;
+    // Last shutdown step, after the webhook server stopped and MQTT published the pending updates and went offline
+    let unregister = async {
+        context.mqtt_offline.cancelled().await;
+        info!("Unregistering webhooks...");
+        clear_bridge_subscriptions(context, &registered_configuration, Some(active_subscriptions)).await
+    };
+    if tokio::time::timeout_at(context.shutdown_deadline(), unregister).await.is_err() {
+        warn!("Webhooks not unregistered within {} seconds", registered_configuration.shutdown_timeout_seconds);
+    }
 }
 
-async fn clear_active_subscriptions(context: &Context, active_subscriptions: Option<Vec<smarther::model::SubscriptionInfo>>) -> Vec<smarther::model::SubscriptionInfo> {
+/// Unregisters the given subscriptions, or all those of the account when `None`, that belong to the
+/// bridge as configured in `configuration`. Returns the ones that could not be unregistered.
+async fn clear_bridge_subscriptions(context: &Context, configuration: &BridgeConfiguration, active_subscriptions: Option<Vec<SubscriptionInfo>>) -> Vec<SubscriptionInfo> {
     if context.refresh_token_if_needed().await.is_err() {
         error!("Failed to refresh token");
         return vec!();
     }
 
-    let client = SmartherApi::default();
-    let auth_request = client.with_authorization(context.auth_info.borrow().clone());
-    if auth_request.is_err() {
-        error!("Failed to create authorized client");
-        return vec!();
-    }
-
-    let client = auth_request.unwrap();
-    //FIXME: Right now we cancel all subscriptions, even if they are not related to this bridge
+    let auth_info = context.auth_info.borrow().clone();
     let active_subscriptions = match active_subscriptions {
         Some(subscriptions) => subscriptions,
-        None => {
-            if let Ok(subscriptions) = client.get_webhooks().await {
-                subscriptions
-            } else {
-                vec!()
-            }
-        }
+        None => context.backend.get_webhooks(&auth_info).await.unwrap_or_default()
     };
 
     let mut remaining_subscriptions = vec!();
-    for subscription in &active_subscriptions {
+    for subscription in active_subscriptions.iter().filter(|subscription| is_bridge_subscription(configuration, subscription)) {
         if let Some(plant_id) = &subscription.plant_id {
-            let result = client.unregister_webhook(plant_id, &subscription.subscription_id).await;
+            let result = context.backend.unregister_webhook(&auth_info, plant_id, &subscription.subscription_id).await;
             if result.is_err() {
                 error!("Failed to unregister webhook {}: {}", &subscription.subscription_id, result.err().unwrap());
                 remaining_subscriptions.push(subscription.clone());
@@ -126,32 +200,145 @@ async fn clear_active_subscriptions(context: &Context, active_subscriptions: Opt
 
 async fn http_server(context: &Context, cancellation_token: CancellationToken) {
     //Wait for events
+    let mut configuration_updates = context.configuration.subscribe();
     let active_plants: Vec<String> = context.topology_cache.plants.iter().map(|plant| plant.id.clone()).collect();
-    let sender = context.status_updates.0.clone();
-    let listen_host: &str = &context.configuration.listen_host;
-    let listen_port: u16 = context.configuration.listen_port;
-    info!("Starting webhook server on {}:{}", listen_host, listen_port);
+    let recorder = Data::new(EventRecorder::new(context.configuration().webhook_record_file));
 
-    let json_cfg = web::JsonConfig::default()
-        .error_handler(|err, _req| {
-            debug!("Failed to parse JSON: {}", err);
-            error::InternalError::from_response(err, HttpResponse::Conflict().into()).into()
-        });
-
-    if let Ok(server) = HttpServer::new(move || {
-        App::new()
-            .app_data(Data::new((active_plants.clone(), sender.clone())))
-            .app_data(json_cfg.clone())
-            .wrap(Logger::default())
-            .wrap(Logger::new("%a %D %r %{User-Agent}i"))
-            .service(process)
-    })
-    .bind((listen_host, listen_port)) {
-        tokio::select! {
-            _ = cancellation_token.cancelled() => {},
-            _ = server.run() => {}
+    loop {
+        let (listen_host, listen_port) = listener_settings(&configuration_updates.borrow());
+        let shutdown_timeout = configuration_updates.borrow().shutdown_timeout();
+        info!("Starting webhook server on {}:{}", listen_host, listen_port);
+
+        let active_plants = active_plants.clone();
+        let sender = context.status_updates.0.clone();
+        let recorder = recorder.clone();
+        let server = match HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new((active_plants.clone(), sender.clone())))
+                .app_data(recorder.clone())
+                .wrap(Logger::default())
+                .wrap(Logger::new("%a %D %r %{User-Agent}i"))
+                .service(process)
+        })
+        .shutdown_timeout(shutdown_timeout.as_secs())
+        .bind((listen_host.as_str(), listen_port)) {
+            Ok(server) => server.run(),
+            Err(err) => {
+                error!("Failed to bind webhook server on {}:{}: {}", listen_host, listen_port, err);
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => { break; },
+                    _ = section_changed(&mut configuration_updates, listener_settings) => { continue; }
+                }
+            }
+        };
+        let server_handle = server.handle();
+        tokio::pin!(server);
+
+        let shutdown = tokio::select! {
+            _ = cancellation_token.cancelled() => true,
+            _ = &mut server => {
+                cancellation_token.cancel();
+                break;
+            },
+            _ = section_changed(&mut configuration_updates, listener_settings) => false
+        };
+
+        if shutdown {
+            info!("Stopping the webhook server, waiting for requests in progress");
+        } else {
+            info!("Webhook listener changed, stopping the server on {}:{}", listen_host, listen_port);
+        }
+        let stopped = async {
+            let (_, result) = tokio::join!(server_handle.stop(true), &mut server);
+            if let Err(err) = result {
+                error!("Webhook server stopped with an error: {}", err);
+            }
+        };
+        if !shutdown {
+            stopped.await;
+            continue;
+        }
+        if tokio::time::timeout_at(context.shutdown_deadline(), stopped).await.is_err() {
+            warn!("Webhook server requests still in progress after {} seconds", shutdown_timeout.as_secs());
         }
+        break;
+    }
+
+    // No more events can arrive, let the MQTT handler drain the queue
+    context.status_updates.0.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web::Data, App};
+
+    use crate::{BridgeConfiguration, backend::fixtures::{self, PLANT_ID, MODULE_ID}, mqtt::status_message};
 
-        cancellation_token.cancel();
+    use super::{process, webhook_url, clear_bridge_subscriptions};
+
+    fn c2c_events(plant_id: &str, module_id: &str, temperature: f64) -> serde_json::Value {
+        serde_json::json!([{
+            "data": fixtures::module_status_json(plant_id, module_id, temperature),
+            "eventTime": "2023-04-10T10:00:00Z",
+            "eventType": "Microsoft.EventGrid.SubscriptionValidationEvent",
+            "id": "event-1",
+            "subject": "smarther"
+        }])
     }
-}
\ No newline at end of file
+
+    #[actix_web::test]
+    async fn webhook_event_is_published_as_status() {
+        let context = fixtures::context(fixtures::backend());
+        let active_plants = vec!(PLANT_ID.to_string());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new((active_plants, context.status_updates.0.clone())))
+                .service(process)
+        ).await;
+
+        let request = test::TestRequest::post()
+            .uri(&format!("/smarther_bridge/{}", PLANT_ID))
+            .set_json(c2c_events(PLANT_ID, MODULE_ID, 21.0))
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        assert_eq!(body, "OK");
+
+        let module_status = context.status_updates.1.try_recv().unwrap();
+        let (topic, summary) = status_message(&context, &module_status.chronothermostats[0]).unwrap();
+        let summary = serde_json::to_value(summary).unwrap();
+        assert_eq!(topic, "smarther/home/living-room/status");
+        assert!(!summary["temperature"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn webhook_event_for_inactive_plant_is_dropped() {
+        let context = fixtures::context(fixtures::backend());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new((vec!(PLANT_ID.to_string()), context.status_updates.0.clone())))
+                .service(process)
+        ).await;
+
+        let request = test::TestRequest::post()
+            .uri("/smarther_bridge/other-plant")
+            .set_json(c2c_events("other-plant", MODULE_ID, 21.0))
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        assert_eq!(body, "Plant not active");
+        assert!(context.status_updates.1.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn only_bridge_subscriptions_are_unregistered() {
+        let backend = fixtures::backend();
+        let configuration = BridgeConfiguration { webhook_endpoint: Some("https://bridge.example.com".to_string()), ..BridgeConfiguration::default() };
+        backend.add_subscription(PLANT_ID, webhook_url("https://bridge.example.com", PLANT_ID)).unwrap();
+        let foreign = backend.add_subscription(PLANT_ID, "https://other.example.com/hook".to_string()).unwrap();
+        let context = fixtures::context_with_configuration(backend.clone(), configuration.clone());
+
+        let remaining = clear_bridge_subscriptions(&context, &configuration, None).await;
+        assert!(remaining.is_empty());
+        let subscription_ids: Vec<String> = backend.subscriptions().into_iter().map(|subscription| subscription.subscription_id).collect();
+        assert_eq!(subscription_ids, vec!(foreign.subscription_id));
+    }
+}